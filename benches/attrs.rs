@@ -0,0 +1,43 @@
+//! Compares `begin_elem_attrs_raw` against the escaping `begin_elem_attrs`,
+//! run with `cargo bench`. A plain timing loop, so it works on stable
+
+extern crate xml_writer;
+
+use std::hint::black_box;
+use std::io;
+use std::time::{ Duration, Instant };
+use xml_writer::XmlWriter;
+
+const ROWS: usize = 1_000_000;
+// the same attrs, escaped up front for the raw path, so both write the same document
+const ATTRS: [(&str, &str); 4] = [("id", "12345"), ("name", "Widget & Co"), ("price", "19.99"), ("currency", "EUR")];
+const ATTRS_ESCAPED: [(&str, &str); 4] = [("id", "12345"), ("name", "Widget &amp; Co"), ("price", "19.99"), ("currency", "EUR")];
+
+#[allow(unused_must_use)]
+fn run(raw: bool) -> Duration {
+    let mut xml = XmlWriter::new(io::sink());
+    xml.pretty = false;
+    let start = Instant::now();
+    xml.begin_elem("rows");
+    for _ in 0..ROWS {
+        if raw {
+            xml.begin_elem_attrs_raw("row", black_box(&ATTRS_ESCAPED));
+        } else {
+            xml.begin_elem_attrs("row", black_box(&ATTRS));
+        }
+        xml.end_elem();
+    }
+    xml.end_elem();
+    xml.flush();
+    start.elapsed()
+}
+
+fn main() {
+    // warm up, then take the best of a few runs
+    run(false);
+    run(true);
+    let escaped = (0..5).map(|_| run(false)).min().unwrap();
+    let raw = (0..5).map(|_| run(true)).min().unwrap();
+    println!("begin_elem_attrs      {:>8.1} ns/elem", escaped.as_nanos() as f64 / ROWS as f64);
+    println!("begin_elem_attrs_raw  {:>8.1} ns/elem", raw.as_nanos() as f64 / ROWS as f64);
+}
@@ -0,0 +1,97 @@
+use std::io;
+use error::XmlError;
+
+/// Something that can be written as the attributes of an element, e.g. a config struct,
+/// see `XmlWriter::begin_elem_attrs`
+pub trait AsAttributes {
+    /// Call `f` with the name and value of each attribute, in order,
+    /// stopping at the first error
+    fn for_each_attr(&self, f: &mut dyn FnMut(&str, &str) -> io::Result<()>) -> io::Result<()>;
+}
+
+impl AsAttributes for [(&str, &str)] {
+    fn for_each_attr(&self, f: &mut dyn FnMut(&str, &str) -> io::Result<()>) -> io::Result<()> {
+        for &(name, value) in self {
+            f(name, value)?;
+        }
+        Ok(())
+    }
+}
+
+impl<const N: usize> AsAttributes for [(&str, &str); N] {
+    fn for_each_attr(&self, f: &mut dyn FnMut(&str, &str) -> io::Result<()>) -> io::Result<()> {
+        self[..].for_each_attr(f)
+    }
+}
+
+impl AsAttributes for Vec<(&str, &str)> {
+    fn for_each_attr(&self, f: &mut dyn FnMut(&str, &str) -> io::Result<()>) -> io::Result<()> {
+        self[..].for_each_attr(f)
+    }
+}
+
+/// What `AttrSet::push` does with an attribute whose name is already in the set
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Duplicates {
+    /// The value pushed last replaces the earlier one
+    LastWins,
+    /// Pushing fails with `XmlError::DuplicateAttr`
+    Error,
+}
+
+/// Attributes collected from an unordered source, free of duplicates and optionally sorted
+/// by name, so they are written deterministically with `XmlWriter::write_attrs`
+#[derive(Debug, Clone)]
+pub struct AttrSet<'s> {
+    attrs: Vec<(&'s str, &'s str)>,
+    duplicates: Duplicates,
+    sorted: bool,
+}
+
+impl<'s> AttrSet<'s> {
+    /// Create a set keeping the attributes in the order they were first pushed
+    pub fn new(duplicates: Duplicates) -> AttrSet<'s> {
+        AttrSet { attrs: Vec::new(), duplicates, sorted: false }
+    }
+
+    /// Create a set keeping the attributes sorted by name
+    pub fn sorted(duplicates: Duplicates) -> AttrSet<'s> {
+        AttrSet { attrs: Vec::new(), duplicates, sorted: true }
+    }
+
+    /// Add an attribute, what happens to duplicates depends on how the set was created
+    pub fn push(&mut self, name: &'s str, value: &'s str) -> io::Result<()> {
+        let found = if self.sorted {
+            self.attrs.binary_search_by(|&(n, _)| n.cmp(name))
+        } else {
+            self.attrs.iter().position(|&(n, _)| n == name).ok_or(self.attrs.len())
+        };
+        match found {
+            Ok(_) if self.duplicates == Duplicates::Error => Err(XmlError::DuplicateAttr(name.to_string()).into()),
+            Ok(i) => {
+                self.attrs[i].1 = value;
+                Ok(())
+            },
+            Err(i) => {
+                self.attrs.insert(i, (name, value));
+                Ok(())
+            }
+        }
+    }
+
+    /// The number of attributes in the set
+    pub fn len(&self) -> usize {
+        self.attrs.len()
+    }
+
+    /// `true` if no attribute has been pushed yet
+    pub fn is_empty(&self) -> bool {
+        self.attrs.is_empty()
+    }
+}
+
+impl<'s> AsAttributes for AttrSet<'s> {
+    fn for_each_attr(&self, f: &mut dyn FnMut(&str, &str) -> io::Result<()>) -> io::Result<()> {
+        self.attrs.for_each_attr(f)
+    }
+}
@@ -0,0 +1,62 @@
+use std::error::Error;
+use std::fmt;
+use std::io;
+
+/// Misuse detected by the XmlWriter itself, as opposed to failures of the underlying writer.
+/// It is handed out wrapped in an `io::Error` of kind `InvalidInput`, get it back with
+/// `err.get_ref().and_then(|e| e.downcast_ref::<XmlError>())`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum XmlError {
+    /// The name is not a valid XML name
+    InvalidName(String),
+    /// The comment contains `--` or ends in `-`
+    InvalidComment(String),
+    /// The processing instruction data contains `?>`
+    InvalidPi(String),
+    /// The character is not allowed in XML 1.0 documents
+    InvalidChar(char),
+    /// The start tag of the element has already been closed by content or a child element,
+    /// so no more attributes can be written to it
+    ElementAlreadyClosed(String),
+    /// Prolog content like a document type declaration written after the root element has begun
+    NotInProlog,
+    /// Text outside of the root element, which only a fragment may have
+    TextOutsideRoot,
+    /// The attribute has already been added
+    DuplicateAttr(String),
+    /// The document was finished without a root element
+    NoRootElement,
+    /// The XML declaration must be the first thing in a document
+    NotAtDocumentStart,
+    /// A namespace declaration was written after an attribute of the same element
+    NsDeclAfterAttr,
+    /// A document has exactly one root element
+    MultipleRoots,
+}
+
+impl fmt::Display for XmlError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            XmlError::InvalidName(ref name) => write!(f, "invalid XML name {:?}", name),
+            XmlError::InvalidComment(ref comment) => write!(f, "invalid XML comment {:?}", comment),
+            XmlError::InvalidPi(ref data) => write!(f, "invalid processing instruction {:?}", data),
+            XmlError::InvalidChar(c) => write!(f, "character {:?} is not allowed in XML", c),
+            XmlError::ElementAlreadyClosed(ref name) => write!(f, "start tag of element {:?} is already closed", name),
+            XmlError::NotInProlog => write!(f, "the prolog must precede the root element"),
+            XmlError::TextOutsideRoot => write!(f, "text outside of the root element"),
+            XmlError::DuplicateAttr(ref name) => write!(f, "duplicate attribute {:?}", name),
+            XmlError::NoRootElement => write!(f, "no root element"),
+            XmlError::NotAtDocumentStart => write!(f, "the XML declaration must be at the start of the document"),
+            XmlError::NsDeclAfterAttr => write!(f, "namespace declarations must precede the attributes"),
+            XmlError::MultipleRoots => write!(f, "a document must have a single root element"),
+        }
+    }
+}
+
+impl Error for XmlError {}
+
+impl From<XmlError> for io::Error {
+    fn from(err: XmlError) -> io::Error {
+        io::Error::new(io::ErrorKind::InvalidInput, err)
+    }
+}
@@ -4,6 +4,13 @@
 
 #![deny(missing_docs)]
 
+mod attributes;
+mod error;
+mod names;
+mod observer;
 mod xml_writer;
 
-pub use xml_writer::XmlWriter;
+pub use attributes::{ AsAttributes, AttrSet, Duplicates };
+pub use error::XmlError;
+pub use observer::{ NameCounter, Observer };
+pub use xml_writer::{ BoolStyle, Config, PrologItem, XmlWriter };
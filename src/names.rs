@@ -0,0 +1,29 @@
+//! Validation of XML names as per https://www.w3.org/TR/xml/#NT-Name
+//! and https://www.w3.org/TR/xml-names/#NT-NCName
+
+fn is_name_start_char(c: char) -> bool {
+    matches!(c, ':' | 'A'..='Z' | '_' | 'a'..='z' |
+        '\u{C0}'..='\u{D6}' | '\u{D8}'..='\u{F6}' | '\u{F8}'..='\u{2FF}' |
+        '\u{370}'..='\u{37D}' | '\u{37F}'..='\u{1FFF}' | '\u{200C}'..='\u{200D}' |
+        '\u{2070}'..='\u{218F}' | '\u{2C00}'..='\u{2FEF}' | '\u{3001}'..='\u{D7FF}' |
+        '\u{F900}'..='\u{FDCF}' | '\u{FDF0}'..='\u{FFFD}' | '\u{10000}'..='\u{EFFFF}')
+}
+
+fn is_name_char(c: char) -> bool {
+    matches!(c, '-' | '.' | '0'..='9' | '\u{B7}' | '\u{300}'..='\u{36F}' | '\u{203F}'..='\u{2040}')
+        || is_name_start_char(c)
+}
+
+/// `true` if `name` is a valid XML Name, colons included
+pub fn is_name(name: &str) -> bool {
+    let mut chars = name.chars();
+    match chars.next() {
+        Some(c) if is_name_start_char(c) => chars.all(is_name_char),
+        _ => false
+    }
+}
+
+/// `true` if `name` is a valid non-colonized name, e.g. a namespace prefix or a local name
+pub fn is_ncname(name: &str) -> bool {
+    !name.contains(':') && is_name(name)
+}
@@ -0,0 +1,45 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::rc::Rc;
+
+/// Gets notified about what the XmlWriter writes, see `XmlWriter::observe`
+pub trait Observer {
+    /// Called once the start tag of an element has been written, by `begin_elem` as well as
+    /// `elem`, `elem_text` and the like, with the (mapped) name of the element, without its namespace prefix
+    fn on_begin_elem(&mut self, name: &str);
+}
+
+/// An observer counting the elements written per element name.
+/// Clones share their counts, so keep one to read them after the document is written
+#[derive(Debug, Clone, Default)]
+pub struct NameCounter {
+    counts: Rc<RefCell<HashMap<String, usize>>>,
+}
+
+impl NameCounter {
+    /// Create a counter with all counts at zero
+    pub fn new() -> NameCounter {
+        NameCounter::default()
+    }
+
+    /// How many elements named `name` have been begun
+    pub fn count(&self, name: &str) -> usize {
+        self.counts.borrow().get(name).cloned().unwrap_or(0)
+    }
+
+    /// The counts of all element names seen
+    pub fn counts(&self) -> HashMap<String, usize> {
+        self.counts.borrow().clone()
+    }
+}
+
+impl Observer for NameCounter {
+    fn on_begin_elem(&mut self, name: &str) {
+        let mut counts = self.counts.borrow_mut();
+        if let Some(count) = counts.get_mut(name) {
+            *count += 1;
+            return;
+        }
+        counts.insert(name.to_string(), 1);
+    }
+}
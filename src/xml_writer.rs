@@ -1,47 +1,421 @@
+use std::borrow::Cow;
+use std::cmp;
+use std::collections::HashMap;
 use std::io::{ self, Write };
+use std::mem;
+use std::ops::Deref;
+use std::rc::Rc;
 use std::fmt;
+use std::str;
+use attributes::AsAttributes;
+use error::XmlError;
+use names;
+use observer::Observer;
 
 pub type Result = io::Result<()>;
 
+const XINCLUDE_NS: &str = "http://www.w3.org/2001/XInclude";
+
+type NameMap<'a> = Box<dyn Fn(&str) -> Cow<str> + 'a>;
+
+/// An element name, owned only if the name map had to allocate it
+enum Name<'a> {
+    Borrowed(&'a str),
+    Owned(String),
+    Shared(Rc<str>),
+}
+
+impl<'a> Deref for Name<'a> {
+    type Target = str;
+
+    fn deref(&self) -> &str {
+        match *self {
+            Name::Borrowed(name) => name,
+            Name::Owned(ref name) => name,
+            Name::Shared(ref name) => name,
+        }
+    }
+}
+
+impl<'a> fmt::Debug for Name<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Debug::fmt(&**self, f)
+    }
+}
+
+/// An item of the document prolog, see `XmlWriter::prolog_items`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PrologItem<'p> {
+    /// A comment, see `XmlWriter::comment`
+    Comment(&'p str),
+    /// A processing instruction with target and data, see `XmlWriter::pi`
+    Pi(&'p str, &'p str),
+    /// A document type declaration with name and external id, see `XmlWriter::doctype`
+    Doctype(&'p str, Option<&'p str>),
+}
+
+/// A snapshot of the options of an `XmlWriter`, e.g. to write a nested document the same way,
+/// see `XmlWriter::config` and `XmlWriter::set_config`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Config<'a> {
+    /// see `XmlWriter::pretty`
+    pub pretty: bool,
+    /// see `XmlWriter::namespace`
+    pub namespace: Option<&'a str>,
+    /// see `XmlWriter::default_namespace`
+    pub default_namespace: Option<&'a str>,
+    /// see `XmlWriter::ns_decls_first`
+    pub ns_decls_first: bool,
+    /// see `XmlWriter::tight_comments`
+    pub tight_comments: bool,
+    /// see `XmlWriter::escape_apostrophe`
+    pub escape_apostrophe: bool,
+    /// see `XmlWriter::space_before_slash`
+    pub space_before_slash: bool,
+    /// see `XmlWriter::newline`
+    pub newline: &'a str,
+    /// see `XmlWriter::indent_unit`
+    pub indent_unit: &'a str,
+    /// see `XmlWriter::max_render_depth`
+    pub max_render_depth: Option<usize>,
+    /// see `XmlWriter::fragment`
+    pub fragment: bool,
+    /// see `XmlWriter::bool_style`
+    pub bool_style: BoolStyle,
+    /// see `XmlWriter::blank_line_between_top_level`
+    pub blank_line_between_top_level: bool,
+    /// see `XmlWriter::trim_trailing_whitespace`
+    pub trim_trailing_whitespace: bool,
+    /// see `XmlWriter::auto_flush_depth`
+    pub auto_flush_depth: Option<usize>,
+    /// see `XmlWriter::comment_wrap`
+    pub comment_wrap: Option<usize>,
+    /// see `XmlWriter::final_newline`
+    pub final_newline: bool,
+    /// see `XmlWriter::normalize_newlines`
+    pub normalize_newlines: bool,
+}
+
+/// How booleans are written by `elem_bool` and `attr_bool`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BoolStyle {
+    /// `true` / `false`
+    TrueFalse,
+    /// `1` / `0`
+    OneZero,
+    /// `yes` / `no`
+    YesNo,
+}
+
+impl BoolStyle {
+    fn format(self, value: bool) -> &'static str {
+        match (self, value) {
+            (BoolStyle::TrueFalse, true) => "true",
+            (BoolStyle::TrueFalse, false) => "false",
+            (BoolStyle::OneZero, true) => "1",
+            (BoolStyle::OneZero, false) => "0",
+            (BoolStyle::YesNo, true) => "yes",
+            (BoolStyle::YesNo, false) => "no",
+        }
+    }
+}
+
 /// The XmlWriter himself
 pub struct XmlWriter<'a, W: Write> {
-    stack: Vec<&'a str>,
+    stack: Vec<Name<'a>>,
     ns_stack: Vec<Option<&'a str>>,
+    default_ns_stack: Vec<Option<&'a str>>,
     writer: Box<W>,
     opened: bool,
+    written: usize,
+    name_map: Option<NameMap<'a>>,
+    interner: Option<HashMap<String, Rc<str>>>,
+    observer: Option<Box<dyn Observer + 'a>>,
+    attr_buf: Vec<u8>,
+    deferring: bool,
+    attrs_written: bool,
+    default_ns_declared: bool,
+    line_start: bool,
+    in_cdata: bool,
+    cdata_brackets: u8,
+    cdata_cr: bool,
+    pending_ws: Vec<u8>,
+    root_started: bool,
+    root_has_child: bool,
+    elided: bool,
+    error: Option<io::Error>,
     /// if `true` it will indent all opening elements
     pub pretty: bool,
     /// an XML namespace that all elements will be part of, unless `None`
     pub namespace: Option<&'a str>,
+    /// the default namespace URI of the elements, declared with `xmlns="..."` on the root element
+    /// and again on every element where it changes, unless `None`
+    pub default_namespace: Option<&'a str>,
+    /// if `true` the attributes of an element are held back until its start tag is closed,
+    /// so that all namespace declarations precede them regardless of call order
+    pub ns_decls_first: bool,
+    /// if `true` comments are written as `<!--text-->`, without the padding spaces
+    pub tight_comments: bool,
+    /// if `false` the `'` is written as is instead of `&apos;`, which some HTML parsers don't know.
+    /// Attribute values are always enclosed in `"`, so this is safe for them too
+    pub escape_apostrophe: bool,
+    /// if `true` self-closing elements are written as `<br />`, as XHTML served to HTML parsers wants them
+    pub space_before_slash: bool,
+    /// the line ending used in pretty mode, `"\n"` unless set otherwise
+    pub newline: &'a str,
+    /// the indentation per level in pretty mode, two spaces unless set otherwise,
+    /// e.g. `"│ "` to draw guides in deeply nested documents
+    pub indent_unit: &'a str,
+    /// if set, only that many levels of elements are written, deeper ones are replaced
+    /// by a `<!-- ... -->`, e.g. for a preview of a huge document
+    pub max_render_depth: Option<usize>,
+    /// if `true` a fragment is written instead of a document, which may have text and several elements at the top level
+    pub fragment: bool,
+    /// how `elem_bool` and `attr_bool` write booleans, `true`/`false` unless set otherwise
+    pub bool_style: BoolStyle,
+    /// if `true` the children of the root element are separated by a blank line in pretty mode
+    pub blank_line_between_top_level: bool,
+    /// if `true` no line ends in indentation, it is held back until something follows on the same line.
+    /// Whitespace in texts, attr values and the like is written as is
+    pub trim_trailing_whitespace: bool,
+    /// if set, the Writer is flushed whenever an element at that depth is closed, the root being
+    /// at depth 1, e.g. `Some(2)` lets the consumer of a stream see every child of the root at once
+    pub auto_flush_depth: Option<usize>,
+    /// if set, comments longer than that many characters are wrapped at spaces in pretty mode,
+    /// the continuation lines are indented to the depth of the comment
+    pub comment_wrap: Option<usize>,
+    /// if `true` `end_document` ends the document with `newline`
+    pub final_newline: bool,
+    /// if `true` the line breaks in comments and CDATA sections, be it `\n`, `\r\n` or `\r`,
+    /// are written as `newline`, so the output has consistent line endings
+    pub normalize_newlines: bool,
 }
 
 impl<'a, W: Write> fmt::Debug for XmlWriter<'a, W> {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        Ok(try!(write!(f, "XmlWriter {{ stack: {:?}, opened: {} }}", self.stack, self.opened)))
+        write!(f, "XmlWriter {{ stack: {:?}, opened: {} }}", self.stack, self.opened)
     }
 }
 
 impl<'a, W: Write> XmlWriter<'a, W> {
     /// Create a new writer, by passing an `io::Write`
     pub fn new(writer: W) -> XmlWriter<'a, W>{
-        XmlWriter { stack: Vec::new(), ns_stack: Vec::new(), writer: Box::new(writer), opened: false, pretty: true, namespace: None, }
+        XmlWriter {
+            stack: Vec::new(),
+            ns_stack: Vec::new(),
+            default_ns_stack: Vec::new(),
+            writer: Box::new(writer),
+            opened: false,
+            written: 0,
+            name_map: None,
+            interner: None,
+            observer: None,
+            attr_buf: Vec::new(),
+            deferring: false,
+            attrs_written: false,
+            default_ns_declared: false,
+            line_start: true,
+            in_cdata: false,
+            cdata_brackets: 0,
+            cdata_cr: false,
+            pending_ws: Vec::new(),
+            root_started: false,
+            root_has_child: false,
+            elided: false,
+            error: None,
+            pretty: true,
+            namespace: None,
+            default_namespace: None,
+            ns_decls_first: false,
+            tight_comments: false,
+            escape_apostrophe: true,
+            space_before_slash: false,
+            newline: "\n",
+            indent_unit: "  ",
+            max_render_depth: None,
+            fragment: false,
+            bool_style: BoolStyle::TrueFalse,
+            blank_line_between_top_level: false,
+            trim_trailing_whitespace: false,
+            auto_flush_depth: None,
+            comment_wrap: None,
+            final_newline: false,
+            normalize_newlines: false,
+        }
+    }
+
+    /// Create a new writer, which remembers the element names produced by `map_names`.
+    /// A name seen before is then neither mapped nor allocated again, which pays off
+    /// for large documents with many repeated elements, like the rows of a table export
+    pub fn with_interner(writer: W) -> XmlWriter<'a, W> {
+        let mut xml = XmlWriter::new(writer);
+        xml.interner = Some(HashMap::new());
+        xml
+    }
+
+    /// Switch pretty printing on or off, also in the middle of a document.
+    /// It takes effect with the next element or comment written
+    pub fn set_pretty(&mut self, pretty: bool) {
+        self.pretty = pretty;
+    }
+
+    /// Run `f` only in pretty mode, e.g. to add a blank line which compact output does without
+    pub fn if_pretty<F>(&mut self, f: F) -> Result where F: FnOnce(&mut Self) -> Result {
+        if self.pretty { f(self) } else { Ok(()) }
     }
 
-    /// Write the DTD
+    /// Run `f` only if not in pretty mode
+    pub fn if_compact<F>(&mut self, f: F) -> Result where F: FnOnce(&mut Self) -> Result {
+        if self.pretty { Ok(()) } else { f(self) }
+    }
+
+    /// The current options
+    pub fn config(&self) -> Config<'a> {
+        Config {
+            pretty: self.pretty,
+            namespace: self.namespace,
+            default_namespace: self.default_namespace,
+            ns_decls_first: self.ns_decls_first,
+            tight_comments: self.tight_comments,
+            escape_apostrophe: self.escape_apostrophe,
+            space_before_slash: self.space_before_slash,
+            newline: self.newline,
+            indent_unit: self.indent_unit,
+            max_render_depth: self.max_render_depth,
+            fragment: self.fragment,
+            bool_style: self.bool_style,
+            blank_line_between_top_level: self.blank_line_between_top_level,
+            trim_trailing_whitespace: self.trim_trailing_whitespace,
+            auto_flush_depth: self.auto_flush_depth,
+            comment_wrap: self.comment_wrap,
+            final_newline: self.final_newline,
+            normalize_newlines: self.normalize_newlines,
+        }
+    }
+
+    /// Set all options at once, e.g. those of another writer
+    pub fn set_config(&mut self, config: Config<'a>) {
+        self.pretty = config.pretty;
+        self.namespace = config.namespace;
+        self.default_namespace = config.default_namespace;
+        self.ns_decls_first = config.ns_decls_first;
+        self.tight_comments = config.tight_comments;
+        self.escape_apostrophe = config.escape_apostrophe;
+        self.space_before_slash = config.space_before_slash;
+        self.newline = config.newline;
+        self.indent_unit = config.indent_unit;
+        self.max_render_depth = config.max_render_depth;
+        self.fragment = config.fragment;
+        self.bool_style = config.bool_style;
+        self.blank_line_between_top_level = config.blank_line_between_top_level;
+        self.trim_trailing_whitespace = config.trim_trailing_whitespace;
+        self.auto_flush_depth = config.auto_flush_depth;
+        self.comment_wrap = config.comment_wrap;
+        self.final_newline = config.final_newline;
+        self.normalize_newlines = config.normalize_newlines;
+    }
+
+    /// `true` if pretty printing is active
+    pub fn is_pretty(&self) -> bool {
+        self.pretty
+    }
+
+    /// The name of the innermost open element, without its namespace prefix
+    pub fn current_elem(&self) -> Option<&str> {
+        self.stack.last().map(|name| &**name)
+    }
+
+    /// The name of the element enclosing the current one, without its namespace prefix
+    pub fn parent_elem(&self) -> Option<&str> {
+        let len = self.stack.len();
+        if len < 2 {
+            return None;
+        }
+        Some(&self.stack[len - 2])
+    }
+
+    /// Register a function that transforms every element name before it is written,
+    /// e.g. to change its case or add a fixed prefix. End tags reuse the transformed name.
+    /// It replaces a function registered before, the names interned for that one are forgotten
+    pub fn map_names<F>(&mut self, map: F) where F: Fn(&str) -> Cow<str> + 'a {
+        self.name_map = Some(Box::new(map));
+        if let Some(ref mut interner) = self.interner {
+            interner.clear();
+        }
+    }
+
+    /// Register an observer to be notified about the elements written, e.g. a `NameCounter`
+    pub fn observe<O: Observer + 'a>(&mut self, observer: O) {
+        self.observer = Some(Box::new(observer));
+    }
+
+    /// Apply the name map, if one is registered, and intern the result if it had to be allocated
+    fn map_name<'n>(&mut self, name: &'n str) -> Name<'n> {
+        let map = match self.name_map {
+            Some(ref map) => map,
+            None => return Name::Borrowed(name)
+        };
+        if let Some(shared) = self.interner.as_ref().and_then(|interner| interner.get(name)) {
+            return Name::Shared(shared.clone());
+        }
+        let mapped = map(name);
+        match mapped {
+            Cow::Borrowed(mapped) => Name::Borrowed(mapped),
+            Cow::Owned(mapped) => match self.interner {
+                Some(ref mut interner) => {
+                    let shared: Rc<str> = Rc::from(mapped);
+                    interner.insert(name.to_string(), shared.clone());
+                    Name::Shared(shared)
+                },
+                None => Name::Owned(mapped)
+            }
+        }
+    }
+
+    /// Write the DTD, followed by `newline` in pretty mode.
+    /// It must be the first thing in a document, see `start_document`
     pub fn dtd(&mut self, encoding: &str) -> Result {
-        try!(self.write("<?xml version=\"1.0\" encoding=\""));
-        try!(self.write(encoding));
-        self.write("\" ?>\n")
+        if self.has_content() {
+            return Err(XmlError::NotAtDocumentStart.into());
+        }
+        self.write("<?xml version=\"1.0\" encoding=\"")?;
+        self.write(encoding)?;
+        self.write("\" ?>")?;
+        if self.pretty {
+            let newline = self.newline;
+            self.write(newline)?;
+        }
+        Ok(())
     }
 
     fn indent(&mut self) -> Result {
         if self.pretty {
-            if self.stack.len() > 0 {
-                try!(self.write("\n"));
-                let indent = self.stack.len() * 2;
-                for _ in 0..indent { try!(self.write(" ")); };
+            // also top level nodes like prolog comments and PIs go on their own lines
+            let newline = self.newline;
+            if !self.line_start {
+                self.write(newline)?;
+            }
+            if self.stack.len() == 1 && self.blank_line_between_top_level {
+                if self.root_has_child {
+                    self.write(newline)?;
+                }
+                self.root_has_child = true;
             }
+            self.write_indent()?;
+        }
+        Ok(())
+    }
+
+    /// Write `indent_unit` for every open element, held back if `trim_trailing_whitespace`
+    /// is set, until it is clear whether something follows on the same line
+    fn write_indent(&mut self) -> Result {
+        let unit = self.indent_unit;
+        if self.trim_trailing_whitespace {
+            self.pending_ws.clear();
+            for _ in 0..self.stack.len() { self.pending_ws.extend_from_slice(unit.as_bytes()); };
+            return Ok(());
         }
+        for _ in 0..self.stack.len() { self.write(unit)?; };
         Ok(())
     }
 
@@ -49,82 +423,280 @@ impl<'a, W: Write> XmlWriter<'a, W> {
     /// if there is one set
     fn ns_prefix(&mut self, namespace: Option<&'a str>) -> Result {
         if let Some(ns) = namespace {
-            try!(self.write(ns));
-            try!(self.write(":"));
+            self.write(ns)?;
+            self.write(":")?;
         }
         Ok(())
     }
 
-    /// Writes namespace declarations (xmlns:xx) into the currently open element
+    /// Writes namespace declarations (xmlns:xx) into the currently open element, the URIs are escaped.
+    /// They must come before any attr of the element, unless `ns_decls_first` is set,
+    /// which moves the attrs behind them. The default namespace can be declared only once per element,
+    /// including the declaration written for `default_namespace`
     pub fn ns_decl(&mut self, ns_map: &Vec<(Option<&'a str>, &'a str)>) -> Result {
-        if !self.opened {
-            panic!("Attempted to write namespace decl to elem, when no elem was opened, stack {:?}", self.stack);
+        self.check_opened("namespace decl")?;
+        if self.attrs_written && !self.ns_decls_first {
+            return Err(XmlError::NsDeclAfterAttr.into());
         }
+        let defaults = ns_map.iter().filter(|item| item.0.is_none()).count();
+        if defaults > 1 || (defaults == 1 && self.default_ns_declared) {
+            return Err(XmlError::DuplicateAttr("xmlns".to_string()).into());
+        }
+        self.write_ns_decls(ns_map)?;
+        if defaults == 1 {
+            self.default_ns_declared = true;
+        }
+        Ok(())
+    }
 
+    fn write_ns_decls(&mut self, ns_map: &[(Option<&str>, &str)]) -> Result {
         for item in ns_map {
-            let name = match item.0 {
-                Some(pre) => {
-                    "xmlns:".to_string() + pre
-                },
-                None => {
-                    "xmlns".to_string()
-                }
-            };
-            try!(self.attr(&name, item.1));
+            check_chars(item.1)?;
+        }
+        for item in ns_map {
+            self.write(" xmlns")?;
+            if let Some(pre) = item.0 {
+                self.write(":")?;
+                self.write(pre)?;
+            }
+            // namespace URIs may well contain `&` in query strings
+            self.write("=\"")?;
+            self.escape(item.1)?;
+            self.write("\"")?;
         }
         Ok(())
     }
 
     /// Write a self-closing element like <br/>
     pub fn elem(&mut self, name: &str) -> Result {
-        try!(self.close_elem());
-        try!(self.indent());
-        try!(self.write("<"));
+        if self.elide()? {
+            return Ok(());
+        }
+        let name = self.map_name(name);
+        self.start_tag()?;
         let ns = self.namespace;
-        try!(self.ns_prefix(ns));
-        try!(self.write(name));
-        self.write("/>")
+        self.ns_prefix(ns)?;
+        self.write(&name)?;
+        self.default_ns_decl()?;
+        self.notify(&name);
+        self.end_empty()
+    }
+
+    /// Write the end of a self-closing element
+    fn end_empty(&mut self) -> Result {
+        self.write(if self.space_before_slash { " />" } else { "/>" })?;
+        let depth = self.stack.len() + 1;
+        self.auto_flush(depth)
+    }
+
+    /// Flush if an element at `auto_flush_depth` has just been closed
+    fn auto_flush(&mut self, depth: usize) -> Result {
+        if self.auto_flush_depth == Some(depth) {
+            self.flush()
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Write an XInclude `<xi:include href="..."/>`, which declares the XInclude namespace itself
+    pub fn xinclude(&mut self, href: &str) -> Result {
+        check_chars(href)?;
+        if self.elide()? {
+            return Ok(());
+        }
+        self.start_tag()?;
+        self.write("xi:include")?;
+        self.default_ns_decl()?;
+        self.write_ns_decls(&[(Some("xi"), XINCLUDE_NS)])?;
+        self.notify("include");
+        self.write(" href=\"")?;
+        self.escape(href)?;
+        self.write("\"")?;
+        self.end_empty()
     }
 
     /// Write an element with inlined text (escaped)
     pub fn elem_text(&mut self, name: &str, text: &str) -> Result {
-        try!(self.close_elem());
-        try!(self.indent());
-        try!(self.write("<"));
+        check_chars(text)?;
+        self.elem_with(name, |xml| xml.escape(text))
+    }
+
+    /// Write an element with the body as is, e.g. trusted HTML from a sanitizer.
+    /// The body is NOT escaped nor checked in any way, it must be well-formed markup from
+    /// a trusted source, otherwise the document breaks or worse. Use at own risk
+    pub fn elem_raw_body(&mut self, name: &str, raw: &str) -> Result {
+        self.elem_with(name, |xml| xml.write(raw))
+    }
+
+    /// Write an element with inlined text (escaped) if there is a text, nothing for `None`
+    pub fn elem_text_opt(&mut self, name: &str, text: Option<&str>) -> Result {
+        match text {
+            Some(text) => self.elem_text(name, text),
+            None => Ok(())
+        }
+    }
+
+    /// Write an element with inlined text (escaped) for each of the values,
+    /// e.g. the cells of a row
+    pub fn elem_text_each(&mut self, name: &str, values: &[&str]) -> Result {
+        for value in values {
+            self.elem_text(name, value)?;
+        }
+        Ok(())
+    }
+
+    /// Write an element with the formatted value as inlined text (escaped),
+    /// without allocating an intermediate `String`
+    pub fn elem_display<V: fmt::Display>(&mut self, name: &str, value: V) -> Result {
+        check_display(&value)?;
+        self.elem_with(name, |xml| xml.escape_display(value))
+    }
+
+    /// Write an element with the boolean as inlined text, formatted as per `bool_style`
+    pub fn elem_bool(&mut self, name: &str, value: bool) -> Result {
+        let value = self.bool_style.format(value);
+        self.elem_with(name, |xml| xml.write(value))
+    }
+
+    /// Write an element whose body is written by `body`
+    fn elem_with<F>(&mut self, name: &str, body: F) -> Result where F: FnOnce(&mut Self) -> Result {
+        if self.elide()? {
+            return Ok(());
+        }
+        let name = self.map_name(name);
+        self.start_tag()?;
         let ns = self.namespace;
-        try!(self.ns_prefix(ns));
-        try!(self.write(name));
-        try!(self.write(">"));
+        self.ns_prefix(ns)?;
+        self.write(&name)?;
+        self.default_ns_decl()?;
+        self.notify(&name);
+        self.write(">")?;
 
-        try!(self.escape(text, false));
+        body(self)?;
 
-        try!(self.write("</"));
-        try!(self.write(name));
-        self.write(">")
+        self.write("</")?;
+        self.ns_prefix(ns)?;
+        self.write(&name)?;
+        self.write(">")?;
+        let depth = self.stack.len() + 1;
+        self.auto_flush(depth)
     }
 
-    /// Begin an elem, make sure name contains only allowed chars
+    /// Begin an elem, make sure name contains only allowed chars.
+    /// A prefixed name like `st:node` is split on the `:` and its prefix is used
+    /// for the start and end tag instead of `namespace`
     pub fn begin_elem(&mut self, name: &'a str) -> Result {
-        try!(self.close_elem());
-        try!(self.indent());
-        self.stack.push(name);
-        self.ns_stack.push(self.namespace);
-        try!(self.write("<"));
+        let (ns, name) = self.split_name(name)?;
+        let name = self.map_name(name);
+        if self.elide()? {
+            // keep track of it for end_elem, but nothing is written until we are back up
+            self.stack.push(name);
+            self.ns_stack.push(ns);
+            let default_ns = self.default_namespace;
+            self.default_ns_stack.push(default_ns);
+            self.opened = true;
+            self.attrs_written = false;
+            return Ok(());
+        }
+        self.start_tag()?;
         self.opened = true;
+        self.attrs_written = false;
         // stderr().write_fmt(format_args!("\nbegin {}", name));
-        let ns = self.namespace;
-        try!(self.ns_prefix(ns));
-        self.write(name)
+        self.ns_prefix(ns)?;
+        self.write(&name)?;
+        let default_ns = self.default_ns_decl()?;
+        self.notify(&name);
+        self.stack.push(name);
+        self.ns_stack.push(ns);
+        self.default_ns_stack.push(default_ns);
+        Ok(())
+    }
+
+    /// Tell the observer about an element whose start tag has been written
+    fn notify(&mut self, name: &str) {
+        if let Some(ref mut observer) = self.observer {
+            observer.on_begin_elem(name);
+        }
+    }
+
+    /// Declare `default_namespace` on the element being written if it differs from
+    /// the one in scope, returns the default namespace in scope for its content
+    fn default_ns_decl(&mut self) -> io::Result<Option<&'a str>> {
+        let in_scope = self.default_ns_stack.last().cloned().unwrap_or(None);
+        self.default_ns_declared = self.default_namespace != in_scope;
+        if self.default_ns_declared {
+            // undeclare the default with xmlns="" if it is unset in the middle of the tree
+            self.write_ns_decls(&[(None, self.default_namespace.unwrap_or(""))])?;
+        }
+        Ok(self.default_namespace)
+    }
+
+    /// `true` if an element begun now would be deeper than `max_render_depth`.
+    /// The elided children of an element are replaced by a single `<!-- ... -->`
+    fn elide(&mut self) -> io::Result<bool> {
+        match self.max_render_depth {
+            Some(max) if self.stack.len() >= max => {
+                if self.stack.len() == max && !self.elided {
+                    self.comment("...")?;
+                    self.elided = true;
+                }
+                Ok(true)
+            },
+            _ => {
+                self.elided = false;
+                Ok(false)
+            }
+        }
+    }
+
+    /// Begin a start tag on a new line, closing the parent's start tag if needed
+    fn start_tag(&mut self) -> Result {
+        if self.stack.is_empty() && self.root_started && !self.fragment {
+            return Err(XmlError::MultipleRoots.into());
+        }
+        // it may be declared on this element, which must not be left half written
+        check_chars(self.default_namespace.unwrap_or(""))?;
+        self.close_elem()?;
+        self.indent()?;
+        if self.stack.is_empty() {
+            self.root_has_child = false;
+        }
+        self.root_started = true;
+        self.write("<")
+    }
+
+    /// Begin an elem and write the attrs into it, see `write_attrs`
+    pub fn begin_elem_attrs<A: AsAttributes + ?Sized>(&mut self, name: &'a str, attrs: &A) -> Result {
+        self.begin_elem(name)?;
+        self.write_attrs(attrs)
+    }
+
+    /// Split a possibly prefixed name into its namespace prefix and local name,
+    /// falling back to `namespace` if there is no prefix
+    fn split_name(&self, name: &'a str) -> io::Result<(Option<&'a str>, &'a str)> {
+        match name.find(':') {
+            None => Ok((self.namespace, name)),
+            Some(i) => {
+                let (prefix, local) = (&name[..i], &name[i + 1..]);
+                if !names::is_ncname(prefix) || !names::is_ncname(local) {
+                    return Err(XmlError::InvalidName(name.to_string()).into());
+                }
+                Ok((Some(prefix), local))
+            }
+        }
     }
 
     /// Close an elem if open, do nothing otherwise
     fn close_elem(&mut self) -> Result {
         if self.opened {
-            if self.pretty {
-                try!(self.write(">"));
-            } else {
-                try!(self.write(">"));
+            if !self.attr_buf.is_empty() {
+                let mut attrs = mem::take(&mut self.attr_buf);
+                let res = self.write_out(&attrs);
+                attrs.clear();
+                self.attr_buf = attrs;
+                res?;
             }
+            self.write(">")?;
             self.opened = false;
         }
         Ok(())
@@ -132,71 +704,182 @@ impl<'a, W: Write> XmlWriter<'a, W> {
 
     /// End and elem
     pub fn end_elem(&mut self) -> Result {
-        try!(self.close_elem());
-        let ns = self.ns_stack.pop().expect(&format!("Attempted to close namespaced element without corresponding open namespace, stack {:?}", self.ns_stack));
+        // every open element has exactly one entry on the namespace stack
+        debug_assert_eq!(self.stack.len(), self.ns_stack.len(),
+            "Element and namespace stack out of sync, stack {:?}, ns_stack {:?}", self.stack, self.ns_stack);
+        if self.in_cdata {
+            self.end_cdata()?;
+        }
+        self.close_elem()?;
+        let depth = self.stack.len();
+        self.default_ns_stack.pop();
+        let ns = match self.ns_stack.pop() {
+            Some(ns) => ns,
+            None => panic!("Attempted to close namespaced element without corresponding open namespace, stack {:?}", self.ns_stack)
+        };
         match self.stack.pop() {
             Some(name) => {
-                try!(self.write("</"));
-                try!(self.ns_prefix(ns));
-                try!(self.write(name));
-                if self.pretty {
-                    try!(self.write(">"));
-                } else {
-                    try!(self.write(">"));
+                if let Some(max) = self.max_render_depth {
+                    if depth == max {
+                        self.elided = false;
+                    }
+                    if depth > max {
+                        return Ok(());
+                    }
                 }
-                Ok(())
+                self.write("</")?;
+                self.ns_prefix(ns)?;
+                self.write(&name)?;
+                self.write(">")?;
+                self.auto_flush(depth)
             },
             None => panic!("Attempted to close an elem, when none was open, stack {:?}", self.stack)
         }
     }
 
-    /// Begin an empty elem
+    /// Write an empty elem, same as `elem`
     pub fn empty_elem(&mut self, name: &'a str) -> Result {
-        try!(self.close_elem());
-        try!(self.indent());
-        try!(self.write("<"));
-        let ns = self.namespace;
-        try!(self.ns_prefix(ns));
-        try!(self.write(name));
-        self.write("/>")
+        self.elem(name)
     }
 
     /// Write an attr, make sure name and value contain only allowed chars.
     /// For an escaping version use `attr_esc`
-    pub fn attr(&mut self, name: &str, value: &str) -> Result {
-        if !self.opened {
-            panic!("Attempted to write attr to elem, when no elem was opened, stack {:?}", self.stack);
-        }
-        try!(self.write(" "));
-        try!(self.write(name));
-        try!(self.write("=\""));
-        try!(self.write(value));
+    pub fn attr(&mut self, name: impl AsRef<str>, value: impl AsRef<str>) -> Result {
+        let (name, value) = (name.as_ref(), value.as_ref());
+        self.check_opened("attr")?;
+        self.deferred(|xml| xml.raw_attr(name, value))
+    }
+
+    fn raw_attr(&mut self, name: &str, value: &str) -> Result {
+        self.write(" ")?;
+        self.write(name)?;
+        self.write("=\"")?;
+        self.write(value)?;
         self.write("\"")
     }
 
     /// Write an attr, make sure name contains only allowed chars
-    pub fn attr_esc(&mut self, name: &str, value: &str) -> Result {
+    pub fn attr_esc(&mut self, name: impl AsRef<str>, value: impl AsRef<str>) -> Result {
+        let (name, value) = (name.as_ref(), value.as_ref());
+        check_chars(name)?;
+        check_chars(value)?;
+        self.check_opened("attr")?;
+        self.deferred(|xml| {
+            xml.write(" ")?;
+            xml.escape(name)?;
+            xml.write("=\"")?;
+            xml.escape(value)?;
+            xml.write("\"")
+        })
+    }
+
+    /// Write an attr with the name as is, after checking it is a valid XML name,
+    /// and the value escaped
+    pub fn attr_value_esc(&mut self, name: impl AsRef<str>, value: impl AsRef<str>) -> Result {
+        let (name, value) = (name.as_ref(), value.as_ref());
+        if !names::is_name(name) {
+            return Err(XmlError::InvalidName(name.to_string()).into());
+        }
+        check_chars(value)?;
+        self.check_opened("attr")?;
+        self.deferred(|xml| {
+            xml.write(" ")?;
+            xml.write(name)?;
+            xml.write("=\"")?;
+            xml.escape(value)?;
+            xml.write("\"")
+        })
+    }
+
+    /// Begin an elem and write the attrs into it as they are, the fast path of `begin_elem_attrs`.
+    /// Neither names nor values are checked or escaped, so this is for trusted input only,
+    /// e.g. values of a fixed schema which are known to be escaped already
+    pub fn begin_elem_attrs_raw<A: AsAttributes + ?Sized>(&mut self, name: &'a str, attrs: &A) -> Result {
+        self.begin_elem(name)?;
+        attrs.for_each_attr(&mut |name, value| self.attr(name, value))
+    }
+
+    /// Write all attrs into the currently open element, e.g. an `AttrSet`.
+    /// The names are checked to be valid XML names, the values are escaped
+    pub fn write_attrs<A: AsAttributes + ?Sized>(&mut self, attrs: &A) -> Result {
+        attrs.for_each_attr(&mut |name, value| self.attr_value_esc(name, value))
+    }
+
+    /// Write an attr with the boolean as value, formatted as per `bool_style`
+    pub fn attr_bool(&mut self, name: impl AsRef<str>, value: bool) -> Result {
+        let value = self.bool_style.format(value);
+        self.attr(name, value)
+    }
+
+    /// Write an attr with the formatted value (escaped), without allocating an intermediate `String`.
+    /// The value is written by its `Display` impl, for control over the number format pass
+    /// `format_args!`, e.g. `attr_display("price", format_args!("{:.2}", price))` for fixed decimals
+    pub fn attr_display<V: fmt::Display>(&mut self, name: impl AsRef<str>, value: V) -> Result {
+        let name = name.as_ref();
+        if !names::is_name(name) {
+            return Err(XmlError::InvalidName(name.to_string()).into());
+        }
+        check_display(&value)?;
+        self.check_opened("attr")?;
+        self.deferred(|xml| {
+            xml.write(" ")?;
+            xml.write(name)?;
+            xml.write("=\"")?;
+            xml.escape_display(value)?;
+            xml.write("\"")
+        })
+    }
+
+    /// Write an attr with the value composed from several pieces by `format_args!` (escaped),
+    /// e.g. `attr_fmt("class", format_args!("{} {}", base, modifier))`, without a joined `String`
+    pub fn attr_fmt(&mut self, name: impl AsRef<str>, args: fmt::Arguments) -> Result {
+        self.attr_display(name, args)
+    }
+
+    /// Write an `xml:id` attr, the value must be a valid NCName.
+    /// Keeping ids unique within the document is up to the caller
+    pub fn id(&mut self, value: impl AsRef<str>) -> Result {
+        let value = value.as_ref();
+        if !names::is_ncname(value) {
+            return Err(XmlError::InvalidName(value.to_string()).into());
+        }
+        self.attr("xml:id", value)
+    }
+
+    /// Make sure the start tag of the current element is still open to write `what` into,
+    /// i.e. no content or child element has been written yet
+    fn check_opened(&self, what: &str) -> Result {
         if !self.opened {
-            panic!("Attempted to write attr to elem, when no elem was opened, stack {:?}", self.stack);
+            match self.stack.last() {
+                Some(name) => return Err(XmlError::ElementAlreadyClosed(name.to_string()).into()),
+                None => panic!("Attempted to write {} to elem, when no elem was opened, stack {:?}", what, self.stack)
+            }
         }
-        try!(self.write(" "));
-        try!(self.escape(name, true));
-        try!(self.write("=\""));
-        try!(self.escape(value, false));
-        self.write("\"")
+        Ok(())
+    }
+
+    /// Run `f` with its output held back until the start tag is closed, if `ns_decls_first` is set
+    fn deferred<F>(&mut self, f: F) -> Result where F: FnOnce(&mut Self) -> Result {
+        self.attrs_written = true;
+        self.deferring = self.ns_decls_first;
+        let res = f(self);
+        self.deferring = false;
+        res
     }
 
-    /// Escape identifiers or text
-    fn escape(&mut self, text: &str, ident: bool) -> Result {
+    /// Escape text, characters not allowed in XML 1.0 like
+    /// form feed or vertical tab are rejected
+    fn escape(&mut self, text: &str) -> Result {
+        // nothing is written if the text is rejected, so no half escaped value is left behind
+        check_chars(text)?;
         for c in text.chars() {
             match c {
-                '"'  => try!(self.write("&quot;")),
-                '\'' => try!(self.write("&apos;")),
-                '&'  => try!(self.write("&amp;")),
-                '<'  => try!(self.write("&lt;")),
-                '>'  => try!(self.write("&gt;")),
-                '\\' if ident => try!(self.write("\\\\")),
-                _    => try!(self.write_slice(c.encode_utf8(&mut [0;4]).as_bytes()))
+                '"'  => self.write("&quot;")?,
+                '\'' if self.escape_apostrophe => self.write("&apos;")?,
+                '&'  => self.write("&amp;")?,
+                '<'  => self.write("&lt;")?,
+                '>'  => self.write("&gt;")?,
+                _    => self.write_slice(c.encode_utf8(&mut [0;4]).as_bytes())?
                    // if let Some(len) =  {
                    //      try!(self.writer.write(&self.utf8[0..len])); ()
                    //  } else {
@@ -207,102 +890,1719 @@ impl<'a, W: Write> XmlWriter<'a, W> {
         Ok(())
     }
 
-    /// Write a text, escapes the text automatically
-    pub fn text(&mut self, text: &str) -> Result {
-        try!(self.close_elem());
-        self.escape(text, false)
+    /// Escape a formatted value, the callers check it with `check_display` before writing anything
+    fn escape_display<V: fmt::Display>(&mut self, value: V) -> Result {
+        let mut escaper = Escaper { xml: self, error: None };
+        match fmt::write(&mut escaper, format_args!("{}", value)) {
+            Ok(()) => Ok(()),
+            Err(_) => Err(escaper.error.unwrap_or_else(|| io::Error::other("formatter error")))
+        }
+    }
+
+    /// Write a text, escapes the text automatically.
+    /// Outside of any element only XML whitespace, i.e. spaces, tabs and line breaks,
+    /// is allowed, unless `fragment` is set.
+    /// An empty text is a no-op, the start tag is left open so attrs can still be added
+    pub fn text(&mut self, text: impl AsRef<str>) -> Result {
+        let text = text.as_ref();
+        if text.is_empty() {
+            return Ok(());
+        }
+        if !text.chars().all(is_xml_whitespace) {
+            self.check_in_root()?;
+        }
+        self.close_elem()?;
+        self.escape(text)
+    }
+
+    /// Character data outside of any element is an error, unless `fragment` is set
+    fn check_in_root(&self) -> Result {
+        if self.stack.is_empty() && !self.fragment {
+            return Err(XmlError::TextOutsideRoot.into());
+        }
+        Ok(())
+    }
+
+    /// Write an entity reference `&name;`, e.g. for an entity declared in the DTD like `&nbsp;`
+    pub fn write_entity(&mut self, name: &str) -> Result {
+        if !names::is_name(name) {
+            return Err(XmlError::InvalidName(name.to_string()).into());
+        }
+        self.check_in_root()?;
+        self.close_elem()?;
+        self.write("&")?;
+        self.write(name)?;
+        self.write(";")
+    }
+
+    /// Like `text`, but returns the number of bytes written after escaping
+    pub fn text_counted(&mut self, text: impl AsRef<str>) -> io::Result<usize> {
+        self.counted(|xml| xml.text(text))
+    }
+
+    /// Like `attr`, but returns the number of bytes written
+    pub fn attr_counted(&mut self, name: impl AsRef<str>, value: impl AsRef<str>) -> io::Result<usize> {
+        self.counted(|xml| xml.attr(name, value))
+    }
+
+    /// Like `attr_esc`, but returns the number of bytes written after escaping
+    pub fn attr_esc_counted(&mut self, name: impl AsRef<str>, value: impl AsRef<str>) -> io::Result<usize> {
+        self.counted(|xml| xml.attr_esc(name, value))
+    }
+
+    /// Run `f` and return how many bytes it has written
+    fn counted<F>(&mut self, f: F) -> io::Result<usize> where F: FnOnce(&mut Self) -> Result {
+        let start = self.written;
+        f(self)?;
+        Ok(self.written - start)
+    }
+
+    /// The total number of bytes written to the underlying writer so far
+    pub fn bytes_written(&self) -> usize {
+        self.written
     }
 
     /// Raw write, no escaping, no safety net, use at own risk
     pub fn write(&mut self, text: &str) -> Result {
-        try!(self.writer.write(text.as_bytes()));
-        Ok(())
+        self.write_slice(text.as_bytes())
+    }
+
+    /// Raw write of bytes, no escaping, like `write`. The bytes are checked to be valid UTF-8,
+    /// fails with `ErrorKind::InvalidData` otherwise, without writing anything
+    pub fn write_bytes(&mut self, bytes: &[u8]) -> Result {
+        match str::from_utf8(bytes) {
+            Ok(text) => self.write(text),
+            Err(err) => Err(io::Error::new(io::ErrorKind::InvalidData, err))
+        }
+    }
+
+    /// Raw write of bytes, no escaping and no UTF-8 check. Only for trusted data,
+    /// invalid UTF-8 makes the whole document invalid
+    pub fn write_bytes_unchecked(&mut self, bytes: &[u8]) -> Result {
+        self.write_slice(bytes)
     }
 
     /// Raw write, no escaping, no safety net, use at own risk
     fn write_slice(&mut self, slice: &[u8]) -> Result {
-        try!(self.writer.write(slice));
+        self.check_poisoned()?;
+        if let Some(max) = self.max_render_depth {
+            if self.stack.len() > max {
+                return Ok(());
+            }
+        }
+        if self.deferring {
+            self.attr_buf.extend_from_slice(slice);
+        } else {
+            self.write_out(slice)?;
+            if let Some(&last) = slice.last() {
+                self.line_start = last == b'\n';
+            }
+        }
+        self.written += slice.len();
         Ok(())
     }
 
-    /// Write a CDATA
-    pub fn cdata(&mut self, cdata: &str) -> Result {
-        try!(self.close_elem());
-        try!(self.write("<![CDATA["));
-        try!(self.write(cdata));
-        self.write("]]>")
+    /// Write to the underlying writer, a failure poisons the XmlWriter
+    fn write_out(&mut self, slice: &[u8]) -> Result {
+        self.check_poisoned()?;
+        let res = match self.write_pending_ws(slice) {
+            Ok(()) => self.writer.write_all(slice),
+            err => err
+        };
+        res.map_err(|err| self.poison(err))
     }
 
-    /// Write a comment
-    pub fn comment(&mut self, comment: &str) -> Result {
-        try!(self.close_elem());
-        try!(self.indent());
-        try!(self.write("<!-- "));
-        try!(self.escape(comment, false));
-        self.write(" -->")
+    /// Fail with a copy of the error that poisoned the writer, if any
+    fn check_poisoned(&self) -> Result {
+        match self.error {
+            Some(ref err) => Err(io::Error::new(err.kind(), err.to_string())),
+            None => Ok(())
+        }
     }
 
-    /// Close all open elems
-    pub fn close(&mut self) -> Result {
-        for _ in 0..self.stack.len() {
-            try!(self.end_elem());
+    /// Keep the error, all further writes fail with a copy of it
+    fn poison(&mut self, err: io::Error) -> io::Error {
+        let copy = io::Error::new(err.kind(), err.to_string());
+        self.error = Some(err);
+        copy
+    }
+
+    /// `true` if writing to the underlying writer has failed, after which
+    /// the XmlWriter refuses to write anything to not produce garbage
+    pub fn is_poisoned(&self) -> bool {
+        self.error.is_some()
+    }
+
+    /// Take the error the writer was poisoned with, which also lifts the poisoning.
+    /// Beware the document is most likely broken at that point
+    pub fn take_error(&mut self) -> Option<io::Error> {
+        self.error.take()
+    }
+
+    /// Write the indentation held back by `trim_trailing_whitespace`, unless `next` begins a new line
+    fn write_pending_ws(&mut self, next: &[u8]) -> Result {
+        if !self.pending_ws.is_empty() && !next.is_empty() {
+            if next[0] != b'\n' && next[0] != b'\r' {
+                self.writer.write_all(&self.pending_ws)?;
+                self.written += self.pending_ws.len();
+            }
+            self.pending_ws.clear();
         }
         Ok(())
     }
 
-    /// Flush the underlying Writer
-    pub fn flush(&mut self) -> Result {
-        self.writer.flush()
+    /// Write a CDATA, a `]]>` in it is split over two sections
+    pub fn cdata(&mut self, cdata: impl AsRef<str>) -> Result {
+        let cdata = cdata.as_ref();
+        check_chars(cdata)?;
+        self.begin_cdata()?;
+        self.write_cdata_chunk(cdata)?;
+        self.end_cdata()
     }
 
-    /// Consume the XmlWriter and return the inner Writer
-    pub fn into_inner(self) -> W {
-        *self.writer
+    /// Begin a CDATA section, to be ended with `end_cdata`.
+    /// `end_elem` and `close` end a pending CDATA section themselves.
+    /// Outside of any element it is an error, unless `fragment` is set
+    pub fn begin_cdata(&mut self) -> Result {
+        if self.in_cdata {
+            panic!("Attempted to begin a CDATA section inside a CDATA section, stack {:?}", self.stack);
+        }
+        self.check_in_root()?;
+        self.close_elem()?;
+        self.in_cdata = true;
+        self.cdata_brackets = 0;
+        self.cdata_cr = false;
+        self.write("<![CDATA[")
     }
-}
 
+    /// Stream content into a CDATA section begun with `begin_cdata`, so big payloads
+    /// needn't be held in memory at once. A `]]>` is split over two sections,
+    /// also when it straddles two chunks
+    pub fn write_cdata_chunk(&mut self, chunk: &str) -> Result {
+        if !self.in_cdata {
+            panic!("Attempted to write a CDATA chunk, when no CDATA section was begun, stack {:?}", self.stack);
+        }
+        check_chars(chunk)?;
+        let mut start = 0;
+        for (i, b) in chunk.bytes().enumerate() {
+            match b {
+                b']' => self.cdata_brackets = cmp::min(self.cdata_brackets + 1, 2),
+                b'>' if self.cdata_brackets == 2 => {
+                    self.write(&chunk[start..i])?;
+                    self.write("]]><![CDATA[")?;
+                    start = i;
+                    self.cdata_brackets = 0;
+                },
+                b'\r' | b'\n' if self.normalize_newlines => {
+                    self.write(&chunk[start..i])?;
+                    start = i + 1;
+                    self.cdata_brackets = 0;
+                    // the `\n` of a `\r\n` may well come with the next chunk
+                    if b == b'\r' || !self.cdata_cr {
+                        let newline = self.newline;
+                        self.write(newline)?;
+                    }
+                },
+                _ => self.cdata_brackets = 0
+            }
+            self.cdata_cr = b == b'\r';
+        }
+        self.write(&chunk[start..])
+    }
 
-#[allow(unused_must_use)]
-#[cfg(test)]
-mod tests {
-    use super::XmlWriter;
-    use std::str;
-
-    #[test]
-    fn integration() {
-        let mut nsmap = Vec::new();
-        nsmap.push((None, "http://localhost/"));
-        nsmap.push((Some("st"), "http://127.0.0.1/"));
-        let mut xml = XmlWriter::new(Vec::new());
-        xml.begin_elem("OTDS");
-            xml.ns_decl(&nsmap);
-            xml.comment("nice to see you");
-            xml.namespace = Some("st");
-            xml.empty_elem("success");
-            xml.begin_elem("node");
-                xml.attr_esc("name", "\"123\"");
-                xml.attr("id", "abc");
-                xml.attr("'unescaped'", "\"123\""); // this WILL generate invalid xml
-                xml.text("'text'");
-            xml.end_elem();
-            xml.namespace = None;
-            xml.begin_elem("stuff");
-                xml.cdata("blablab");
-            // xml.end_elem();
-         // xml.end_elem();
-         xml.close();
-         xml.flush();
+    /// End a CDATA section begun with `begin_cdata`
+    pub fn end_cdata(&mut self) -> Result {
+        if !self.in_cdata {
+            panic!("Attempted to end a CDATA section, when none was begun, stack {:?}", self.stack);
+        }
+        self.in_cdata = false;
+        self.write("]]>")
+    }
 
-         let actual = xml.into_inner();
-         assert_eq!(str::from_utf8(&actual).unwrap(), "<OTDS xmlns=\"http://localhost/\" xmlns:st=\"http://127.0.0.1/\">\n  <!-- nice to see you -->\n  <st:success/>\n  <st:node name=\"&quot;123&quot;\" id=\"abc\" \'unescaped\'=\"\"123\"\">&apos;text&apos;</st:node>\n  <stuff><![CDATA[blablab]]></stuff></OTDS>");
+    /// Write a comment, it must not contain `--`,
+    /// nor end in `-` if `tight_comments` is set.
+    /// Markup characters are escaped, so `&amp;` ends up as `&amp;amp;`, see `comment_raw`
+    pub fn comment(&mut self, comment: impl AsRef<str>) -> Result {
+        self.write_comment(comment.as_ref(), true)
     }
 
-    #[test]
-    fn comment() {
-        let mut xml = XmlWriter::new(Vec::new());
-        xml.comment("comment");
+    /// Write a comment as is. Entities aren't recognized in comments, so unlike
+    /// `comment` this writes e.g. `&amp;` literally. The `--` rules apply all the same
+    pub fn comment_raw(&mut self, comment: impl AsRef<str>) -> Result {
+        self.write_comment(comment.as_ref(), false)
+    }
+
+    fn write_comment(&mut self, comment: &str, escape: bool) -> Result {
+        if comment.contains("--") || (self.tight_comments && comment.ends_with('-')) {
+            return Err(XmlError::InvalidComment(comment.to_string()).into());
+        }
+        check_chars(comment)?;
+        self.close_elem()?;
+        self.indent()?;
+        self.write(if self.tight_comments { "<!--" } else { "<!-- " })?;
+        let wrap = if self.pretty { self.comment_wrap } else { None };
+        match wrap {
+            // a dash in the line break could form a `--` with the text around it
+            Some(width) if comment.chars().count() > width
+                && !self.newline.contains('-') && !self.indent_unit.contains('-') => {
+                let (mut len, mut gap) = (0, 0);
+                for (i, word) in comment.split(' ').enumerate() {
+                    if i > 0 {
+                        gap += 1;
+                    }
+                    if word.is_empty() {
+                        continue;
+                    }
+                    let word_len = word.chars().count();
+                    // a run of spaces is replaced by the line break as a whole
+                    if len > 0 && len + gap + word_len > width {
+                        self.comment_break()?;
+                        len = 0;
+                    } else {
+                        for _ in 0..gap { self.write(" ")?; };
+                        len += gap;
+                    }
+                    gap = 0;
+                    self.comment_text(word, escape)?;
+                    len += word_len;
+                }
+                for _ in 0..gap { self.write(" ")?; };
+            },
+            _ => self.comment_text(comment, escape)?
+        }
+        self.write(if self.tight_comments { "-->" } else { " -->" })
+    }
+
+    fn comment_text(&mut self, text: &str, escape: bool) -> Result {
+        let mut start = 0;
+        if self.normalize_newlines {
+            for (i, b) in text.bytes().enumerate() {
+                if b == b'\r' || b == b'\n' {
+                    self.comment_part(&text[start..i], escape)?;
+                    start = i + 1;
+                    if b == b'\r' || i == 0 || text.as_bytes()[i - 1] != b'\r' {
+                        let newline = self.newline;
+                        self.write(newline)?;
+                    }
+                }
+            }
+        }
+        self.comment_part(&text[start..], escape)
+    }
+
+    fn comment_part(&mut self, text: &str, escape: bool) -> Result {
+        if escape {
+            self.escape(text)
+        } else {
+            self.write(text)
+        }
+    }
+
+    /// Continue a wrapped comment on a new line, indented to the depth of the comment
+    fn comment_break(&mut self) -> Result {
+        let newline = self.newline;
+        self.write(newline)?;
+        self.write_indent()
+    }
+
+    /// Write a raw line, no escaping, e.g. for a pre-formatted block like a license text.
+    /// It is indented like an element and followed by `newline`
+    pub fn write_line(&mut self, text: &str) -> Result {
+        self.close_elem()?;
+        self.indent()?;
+        self.write(text)?;
+        let newline = self.newline;
+        self.write(newline)
+    }
+
+    /// Write a processing instruction `<?target data?>`, e.g. in the prolog.
+    /// The data must not contain `?>`
+    pub fn pi(&mut self, target: &str, data: &str) -> Result {
+        check_pi_target(target)?;
+        if data.contains("?>") {
+            return Err(XmlError::InvalidPi(data.to_string()).into());
+        }
+        check_chars(data)?;
+        self.close_elem()?;
+        self.indent()?;
+        self.write("<?")?;
+        self.write(target)?;
+        if !data.is_empty() {
+            self.write(" ")?;
+            self.write(data)?;
+        }
+        self.write("?>")
+    }
+
+    /// Write a processing instruction with pseudo attributes `<?target name="value"?>`.
+    /// The values are escaped like attribute values and must not contain `?>`
+    pub fn pi_attrs(&mut self, target: &str, attrs: &[(&str, &str)]) -> Result {
+        check_pi_target(target)?;
+        for &(name, value) in attrs {
+            if !names::is_name(name) {
+                return Err(XmlError::InvalidName(name.to_string()).into());
+            }
+            if value.contains("?>") {
+                return Err(XmlError::InvalidPi(value.to_string()).into());
+            }
+            check_chars(value)?;
+        }
+        self.close_elem()?;
+        self.indent()?;
+        self.write("<?")?;
+        self.write(target)?;
+        for &(name, value) in attrs {
+            self.write(" ")?;
+            self.write(name)?;
+            self.write("=\"")?;
+            self.escape(value)?;
+            self.write("\"")?;
+        }
+        self.write("?>")
+    }
+
+    /// Write an `<?xml-stylesheet type="..." href="..."?>` in the prolog
+    pub fn stylesheet(&mut self, type_: &str, href: &str) -> Result {
+        self.pi_attrs("xml-stylesheet", &[("type", type_), ("href", href)])
+    }
+
+    /// Associate a schema with the document by `<?xml-model href="..." type="..."?>`,
+    /// e.g. `xml_model("note.rnc", "application/relax-ng-compact-syntax")`, before the root element
+    pub fn xml_model(&mut self, href: &str, type_: &str) -> Result {
+        if self.root_started {
+            return Err(XmlError::NotInProlog.into());
+        }
+        self.pi_attrs("xml-model", &[("href", href), ("type", type_)])
+    }
+
+    /// Write a document type declaration `<!DOCTYPE name external_id>`, before the root element.
+    /// The external id is written as is, e.g. `SYSTEM "note.dtd"`
+    pub fn doctype(&mut self, name: &str, external_id: Option<&str>) -> Result {
+        if self.root_started {
+            return Err(XmlError::NotInProlog.into());
+        }
+        if !names::is_name(name) {
+            return Err(XmlError::InvalidName(name.to_string()).into());
+        }
+        self.indent()?;
+        self.write("<!DOCTYPE ")?;
+        self.write(name)?;
+        if let Some(external_id) = external_id {
+            self.write(" ")?;
+            self.write(external_id)?;
+        }
+        self.write(">")
+    }
+
+    /// Write the comments, PIs and document type declaration making up the prolog in order,
+    /// before the root element
+    pub fn prolog_items(&mut self, items: &[PrologItem]) -> Result {
+        if self.root_started {
+            return Err(XmlError::NotInProlog.into());
+        }
+        for item in items {
+            match *item {
+                PrologItem::Comment(comment) => self.comment(comment)?,
+                PrologItem::Pi(target, data) => self.pi(target, data)?,
+                PrologItem::Doctype(name, external_id) => self.doctype(name, external_id)?
+            }
+        }
+        Ok(())
+    }
+
+    /// Begin a new document, everything written before belongs to the previous one, see `reset`.
+    /// Up to the first write the DTD may be written, up to the root element the rest of the prolog
+    pub fn start_document(&mut self) {
+        self.reset();
+    }
+
+    /// End the document begun with `start_document`, i.e. close all open elems,
+    /// write a `newline` if `final_newline` is set and flush the Writer
+    pub fn end_document(&mut self) -> Result {
+        self.close()?;
+        if self.final_newline && !self.line_start {
+            let newline = self.newline;
+            self.write(newline)?;
+        }
+        self.flush()
+    }
+
+    /// Close all open elems, a CDATA section begun with `begin_cdata` is ended first.
+    /// Calling it again does nothing, so it is safe to call in a cleanup path as well
+    pub fn close(&mut self) -> Result {
+        if self.in_cdata {
+            self.end_cdata()?;
+        }
+        for _ in 0..self.stack.len() {
+            self.end_elem()?;
+        }
+        Ok(())
+    }
+
+    /// `true` if anything has been written since the writer was created or last `reset`
+    pub fn has_content(&self) -> bool {
+        self.written > 0
+    }
+
+    /// Forget about the current document, e.g. to write another one to the same underlying writer.
+    /// Open elements are dropped without being closed, the options are kept.
+    /// The internal buffers keep their capacity, so once warmed up by a first document,
+    /// writing further documents of similar shape with the same XmlWriter does not allocate
+    pub fn reset(&mut self) {
+        self.stack.clear();
+        self.ns_stack.clear();
+        self.default_ns_stack.clear();
+        self.opened = false;
+        self.written = 0;
+        self.attr_buf.clear();
+        self.deferring = false;
+        self.attrs_written = false;
+        self.default_ns_declared = false;
+        self.line_start = true;
+        self.in_cdata = false;
+        self.cdata_brackets = 0;
+        self.cdata_cr = false;
+        self.pending_ws.clear();
+        self.root_started = false;
+        self.root_has_child = false;
+        self.elided = false;
+    }
+
+    /// Flush the underlying Writer, can be called anywhere in the document.
+    /// A pending start tag is closed first, so no more attrs can be added to it afterwards
+    pub fn flush(&mut self) -> Result {
+        self.close_elem()?;
+        self.check_poisoned()?;
+        self.writer.flush().map_err(|err| self.poison(err))
+    }
+
+    /// Close all open elements, flush and return the inner Writer.
+    /// Unless writing a `fragment`, it is an error if no root element has been written
+    pub fn finish(mut self) -> io::Result<W> {
+        self.close()?;
+        self.flush()?;
+        if !self.fragment && !self.root_started {
+            return Err(XmlError::NoRootElement.into());
+        }
+        Ok(*self.writer)
+    }
+
+    /// Consume the XmlWriter and return the inner Writer
+    pub fn into_inner(self) -> W {
+        *self.writer
+    }
+}
+
+/// A PI target must be a name without colon and not `xml`, which is reserved for the declaration
+fn check_pi_target(target: &str) -> Result {
+    if !names::is_ncname(target) || target.eq_ignore_ascii_case("xml") {
+        return Err(XmlError::InvalidName(target.to_string()).into());
+    }
+    Ok(())
+}
+
+/// Characters not allowed in XML 1.0, like form feed or vertical tab, are rejected
+fn check_chars(text: &str) -> Result {
+    match text.chars().find(|&c| is_illegal_char(c)) {
+        Some(c) => Err(XmlError::InvalidChar(c).into()),
+        None => Ok(())
+    }
+}
+
+/// The whitespace of the XML grammar, unlike `char::is_whitespace` without e.g. a no-break space
+fn is_xml_whitespace(c: char) -> bool {
+    matches!(c, ' ' | '\t' | '\n' | '\r')
+}
+
+fn is_illegal_char(c: char) -> bool {
+    matches!(c, '\u{0}'..='\u{8}' | '\u{B}' | '\u{C}' | '\u{E}'..='\u{1F}' | '\u{FFFE}' | '\u{FFFF}')
+}
+
+/// Like `check_chars` for a formatted value, which is formatted once more to be written
+fn check_display<V: fmt::Display>(value: &V) -> Result {
+    let mut checker = CharChecker(None);
+    let _ = fmt::write(&mut checker, format_args!("{}", value));
+    match checker.0 {
+        Some(c) => Err(XmlError::InvalidChar(c).into()),
+        None => Ok(())
+    }
+}
+
+/// Remembers the first character not allowed in XML formatted into it
+struct CharChecker(Option<char>);
+
+impl fmt::Write for CharChecker {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        match s.chars().find(|&c| is_illegal_char(c)) {
+            Some(c) => {
+                self.0 = Some(c);
+                Err(fmt::Error)
+            },
+            None => Ok(())
+        }
+    }
+}
+
+/// Raw passthrough to the underlying writer, e.g. to hand the XmlWriter to code expecting an `io::Write`.
+///
+/// **Beware**: everything written this way bypasses escaping, pretty printing and
+/// the element state entirely, use it only for trusted, pre-built XML. A failure poisons the
+/// XmlWriter all the same, see `is_poisoned`.
+/// Note that the inherent `write` and `flush` methods take precedence over these
+/// when called on an `XmlWriter` directly.
+impl<'a, W: Write> Write for XmlWriter<'a, W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.check_poisoned()?;
+        let res = match self.write_pending_ws(buf) {
+            Ok(()) => self.writer.write(buf),
+            Err(err) => Err(err)
+        };
+        let len = res.map_err(|err| self.poison(err))?;
+        self.written += len;
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> Result {
+        self.check_poisoned()?;
+        self.writer.flush().map_err(|err| self.poison(err))
+    }
+}
+
+/// Escapes everything formatted into it, keeps the io::Error a `fmt::Write` can't return
+struct Escaper<'x, 'a: 'x, W: Write + 'x> {
+    xml: &'x mut XmlWriter<'a, W>,
+    error: Option<io::Error>,
+}
+
+impl<'x, 'a, W: Write> fmt::Write for Escaper<'x, 'a, W> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.xml.escape(s).map_err(|err| {
+            self.error = Some(err);
+            fmt::Error
+        })
+    }
+}
+
+#[allow(unused_must_use, clippy::vec_init_then_push)]
+#[cfg(test)]
+mod tests {
+    use super::{ BoolStyle, PrologItem, XmlWriter };
+    use attributes::{ AsAttributes, AttrSet, Duplicates };
+    use error::XmlError;
+    use observer::NameCounter;
+    use std::borrow::Cow;
+    use std::cell::Cell;
+    use std::fmt;
+    use std::io;
+    use std::str;
+
+    #[test]
+    fn integration() {
+        let mut nsmap = Vec::new();
+        nsmap.push((None, "http://localhost/"));
+        nsmap.push((Some("st"), "http://127.0.0.1/"));
+        let mut xml = XmlWriter::new(Vec::new());
+        xml.begin_elem("OTDS");
+            xml.ns_decl(&nsmap);
+            xml.comment("nice to see you");
+            xml.namespace = Some("st");
+            xml.empty_elem("success");
+            xml.begin_elem("node");
+                xml.attr_esc("name", "\"123\"");
+                xml.attr("id", "abc");
+                xml.attr("'unescaped'", "\"123\""); // this WILL generate invalid xml
+                xml.text("'text'");
+            xml.end_elem();
+            xml.namespace = None;
+            xml.begin_elem("stuff");
+                xml.cdata("blablab");
+            // xml.end_elem();
+         // xml.end_elem();
+         xml.close();
+         xml.flush();
+
+         let actual = xml.into_inner();
+         assert_eq!(str::from_utf8(&actual).unwrap(), "<OTDS xmlns=\"http://localhost/\" xmlns:st=\"http://127.0.0.1/\">\n  <!-- nice to see you -->\n  <st:success/>\n  <st:node name=\"&quot;123&quot;\" id=\"abc\" \'unescaped\'=\"\"123\"\">&apos;text&apos;</st:node>\n  <stuff><![CDATA[blablab]]></stuff></OTDS>");
+    }
+
+    #[test]
+    fn comment() {
+        let mut xml = XmlWriter::new(Vec::new());
+        xml.comment("comment");
 
         let actual = xml.into_inner();
         assert_eq!(str::from_utf8(&actual).unwrap(), "<!-- comment -->");
     }
+
+    #[test]
+    fn comment_raw() {
+        let mut xml = XmlWriter::new(Vec::new());
+        xml.comment_raw("use &amp; for <&>");
+        assert!(xml.comment_raw("a--b").is_err());
+
+        let actual = xml.into_inner();
+        assert_eq!(str::from_utf8(&actual).unwrap(), "<!-- use &amp; for <&> -->");
+    }
+
+    #[test]
+    fn tight_comment() {
+        let mut xml = XmlWriter::new(Vec::new());
+        xml.tight_comments = true;
+        xml.comment("comment");
+        assert!(xml.comment("comment-").is_err());
+        assert!(xml.comment("com--ment").is_err());
+
+        let actual = xml.into_inner();
+        assert_eq!(str::from_utf8(&actual).unwrap(), "<!--comment-->");
+    }
+
+    #[test]
+    fn io_write() {
+        use std::io::Write;
+
+        fn dump(out: &mut dyn Write) {
+            out.write_all(b"<pre built=\"1\"/>").unwrap();
+        }
+
+        let mut xml = XmlWriter::new(Vec::new());
+        xml.begin_elem("root");
+        xml.flush();
+        dump(&mut xml);
+        xml.end_elem();
+
+        let actual = xml.into_inner();
+        assert_eq!(str::from_utf8(&actual).unwrap(), "<root><pre built=\"1\"/></root>");
+    }
+
+    #[test]
+    fn config() {
+        let mut outer = XmlWriter::new(Vec::new());
+        outer.indent_unit = "\t";
+        outer.bool_style = BoolStyle::YesNo;
+        outer.comment_wrap = Some(60);
+
+        let mut inner = XmlWriter::new(Vec::new());
+        inner.set_config(outer.config());
+        assert_eq!(inner.config(), outer.config());
+        assert_eq!(inner.indent_unit, "\t");
+        inner.begin_elem("a");
+            inner.elem_bool("b", true);
+        inner.end_elem();
+
+        let actual = inner.into_inner();
+        assert_eq!(str::from_utf8(&actual).unwrap(), "<a>\n\t<b>yes</b></a>");
+    }
+
+    #[test]
+    fn normalize_newlines() {
+        let mut xml = XmlWriter::new(Vec::new());
+        xml.newline = "\r\n";
+        xml.normalize_newlines = true;
+        xml.begin_elem("a");
+            xml.comment("one\ntwo\r\nthree\rfour\r");
+            xml.cdata("x\ny\r\n]]>z");
+            xml.begin_cdata();
+                xml.write_cdata_chunk("a\r");
+                xml.write_cdata_chunk("\nb\r");
+                xml.write_cdata_chunk("c");
+            xml.end_cdata();
+        xml.end_elem();
+
+        let actual = xml.into_inner();
+        assert_eq!(str::from_utf8(&actual).unwrap(),
+            "<a>\r\n  <!-- one\r\ntwo\r\nthree\r\nfour\r\n --><![CDATA[x\r\ny\r\n]]]]><![CDATA[>z]]><![CDATA[a\r\nb\r\nc]]></a>");
+    }
+
+    #[test]
+    fn if_pretty() {
+        let mut xml = XmlWriter::new(Vec::new());
+        xml.begin_elem("root");
+            xml.if_pretty(|xml| xml.comment("pretty"));
+            xml.if_compact(|xml| xml.comment("compact"));
+            xml.set_pretty(false);
+            xml.if_pretty(|xml| xml.elem("a"));
+            xml.if_compact(|xml| xml.elem("b"));
+        xml.end_elem();
+
+        let actual = xml.into_inner();
+        assert_eq!(str::from_utf8(&actual).unwrap(), "<root>\n  <!-- pretty --><b/></root>");
+    }
+
+    #[test]
+    fn toggle_pretty() {
+        let mut xml = XmlWriter::new(Vec::new());
+        xml.begin_elem("root");
+            xml.elem("a");
+            xml.set_pretty(false);
+            assert!(!xml.is_pretty());
+            xml.begin_elem("b");
+                xml.elem("c");
+            xml.end_elem();
+            xml.set_pretty(true);
+            xml.elem("d");
+        xml.end_elem();
+
+        let actual = xml.into_inner();
+        assert_eq!(str::from_utf8(&actual).unwrap(), "<root>\n  <a/><b><c/></b>\n  <d/></root>");
+    }
+
+    #[test]
+    fn consecutive_empty_elems() {
+        let mut xml = XmlWriter::new(Vec::new());
+        xml.begin_elem("root");
+            xml.empty_elem("a");
+            xml.empty_elem("b");
+            xml.empty_elem("c");
+        xml.end_elem();
+
+        let actual = xml.into_inner();
+        assert_eq!(str::from_utf8(&actual).unwrap(), "<root>\n  <a/>\n  <b/>\n  <c/></root>");
+    }
+
+    #[test]
+    fn unescaped_apostrophe() {
+        let mut xml = XmlWriter::new(Vec::new());
+        xml.escape_apostrophe = false;
+        xml.begin_elem("p");
+            xml.attr_esc("title", "it's \"here\"");
+            xml.text("it's");
+        xml.end_elem();
+
+        let actual = xml.into_inner();
+        assert_eq!(str::from_utf8(&actual).unwrap(), "<p title=\"it's &quot;here&quot;\">it's</p>");
+    }
+
+    #[test]
+    fn prolog() {
+        let mut xml = XmlWriter::new(Vec::new());
+        xml.dtd("utf-8");
+        xml.comment("(c) 2016");
+        xml.pi("xml-stylesheet", "href=\"a.xsl\"");
+        assert!(xml.pi("xml", "").is_err());
+        assert!(xml.pi("target", "?>").is_err());
+        xml.begin_elem("root");
+        xml.end_elem();
+        xml.comment("end");
+
+        let actual = xml.into_inner();
+        assert_eq!(str::from_utf8(&actual).unwrap(), "<?xml version=\"1.0\" encoding=\"utf-8\" ?>\n<!-- (c) 2016 -->\n<?xml-stylesheet href=\"a.xsl\"?>\n<root></root>\n<!-- end -->");
+    }
+
+    #[test]
+    fn space_before_slash() {
+        let mut xml = XmlWriter::new(Vec::new());
+        xml.pretty = false;
+        xml.fragment = true;
+        xml.space_before_slash = true;
+        xml.elem("br");
+        xml.empty_elem("hr");
+
+        let actual = xml.into_inner();
+        assert_eq!(str::from_utf8(&actual).unwrap(), "<br /><hr />");
+    }
+
+    #[test]
+    fn write_line() {
+        let mut xml = XmlWriter::new(Vec::new());
+        xml.newline = "\r\n";
+        xml.begin_elem("license");
+            xml.write_line("Licensed under MIT");
+            xml.write_line("or Apache-2.0");
+            xml.elem("signed");
+        xml.end_elem();
+
+        let actual = xml.into_inner();
+        assert_eq!(str::from_utf8(&actual).unwrap(), "<license>\r\n  Licensed under MIT\r\n  or Apache-2.0\r\n  <signed/></license>");
+    }
+
+    #[test]
+    fn illegal_chars() {
+        let mut xml = XmlWriter::new(Vec::new());
+        xml.begin_elem("a");
+        assert!(xml.text("tab\tnewline\n").is_ok());
+        for text in &["vt\u{B}", "ff\u{C}", "nul\u{0}", "\u{FFFE}"] {
+            let err = xml.text(text).unwrap_err();
+            assert!(err.get_ref().unwrap().downcast_ref::<XmlError>().is_some());
+        }
+    }
+
+    #[test]
+    fn illegal_char_writes_nothing() {
+        for &deferred in &[false, true] {
+            let mut xml = XmlWriter::new(Vec::new());
+            xml.pretty = false;
+            xml.ns_decls_first = deferred;
+            xml.begin_elem("r");
+                assert!(xml.attr_esc("a", "x\u{B}y").is_err());
+                assert!(xml.attr_display("c", format_args!("{}{}", "x", '\u{C}')).is_err());
+                xml.attr("b", "1");
+                assert!(xml.text("ok\u{0}").is_err());
+                assert!(xml.elem_text("e", "\u{B}").is_err());
+                assert!(xml.cdata("\u{C}").is_err());
+                assert!(xml.comment_raw("\u{C}").is_err());
+                assert!(xml.pi("t", "\u{B}").is_err());
+                xml.begin_cdata();
+                    assert!(xml.write_cdata_chunk("a\u{0}").is_err());
+                xml.end_cdata();
+            xml.end_elem();
+
+            let actual = xml.into_inner();
+            assert_eq!(str::from_utf8(&actual).unwrap(), "<r b=\"1\"><![CDATA[]]></r>");
+        }
+    }
+
+    #[test]
+    fn illegal_default_namespace_writes_nothing() {
+        let mut xml = XmlWriter::new(Vec::new());
+        xml.pretty = false;
+        xml.begin_elem("root");
+            xml.default_namespace = Some("urn:\u{B}");
+            assert!(xml.begin_elem("child").is_err());
+            assert!(xml.elem("e").is_err());
+            assert!(xml.elem_text("t", "x").is_err());
+            assert!(xml.xinclude("a.xml").is_err());
+            xml.default_namespace = None;
+            xml.attr("a", "1");
+        xml.end_elem();
+
+        let actual = xml.into_inner();
+        assert_eq!(str::from_utf8(&actual).unwrap(), "<root a=\"1\"></root>");
+    }
+
+    #[test]
+    fn close_pending_cdata() {
+        let mut xml = XmlWriter::new(Vec::new());
+        xml.begin_elem("data");
+            xml.begin_cdata();
+            xml.write("<raw>");
+        xml.close();
+        xml.close();
+
+        let actual = xml.into_inner();
+        assert_eq!(str::from_utf8(&actual).unwrap(), "<data><![CDATA[<raw>]]></data>");
+    }
+
+    #[test]
+    fn cdata_chunks() {
+        let mut xml = XmlWriter::new(Vec::new());
+        xml.fragment = true;
+        xml.cdata("a]]>b");
+        xml.begin_cdata();
+            xml.write_cdata_chunk("c]");
+            xml.write_cdata_chunk("]");
+            xml.write_cdata_chunk(">d]]");
+        xml.end_cdata();
+
+        let actual = xml.into_inner();
+        assert_eq!(str::from_utf8(&actual).unwrap(), "<![CDATA[a]]]]><![CDATA[>b]]><![CDATA[c]]]]><![CDATA[>d]]]]>");
+    }
+
+    #[test]
+    fn trim_trailing_whitespace() {
+        use std::io::Write;
+
+        let mut xml = XmlWriter::new(Vec::new());
+        xml.trim_trailing_whitespace = true;
+        xml.begin_elem("root");
+            xml.text("a \t");
+            xml.begin_elem("b");
+                xml.attr_esc("a", "x \ny");
+                xml.text("keep  \nme ");
+            xml.end_elem();
+            xml.write_line("");
+            xml.write_line("line  ");
+            xml.text("  ");
+            xml.write_all(b"<raw/>");
+        xml.end_elem();
+
+        let actual = xml.into_inner();
+        assert_eq!(str::from_utf8(&actual).unwrap(), "<root>a \t\n  <b a=\"x \ny\">keep  \nme </b>\n\n  line  \n  <raw/></root>");
+    }
+
+    #[test]
+    fn write_entity() {
+        let mut xml = XmlWriter::new(Vec::new());
+        xml.begin_elem("p");
+            xml.text("ACME");
+            xml.write_entity("nbsp");
+            xml.write_entity("company");
+            assert!(xml.write_entity("a b").is_err());
+            assert!(xml.write_entity("").is_err());
+        xml.end_elem();
+
+        let actual = xml.into_inner();
+        assert_eq!(str::from_utf8(&actual).unwrap(), "<p>ACME&nbsp;&company;</p>");
+    }
+
+    #[test]
+    fn dtd_newline() {
+        let mut xml = XmlWriter::new(Vec::new());
+        xml.newline = "\r\n";
+        xml.dtd("utf-8");
+        xml.elem("root");
+
+        let actual = xml.into_inner();
+        assert_eq!(str::from_utf8(&actual).unwrap(), "<?xml version=\"1.0\" encoding=\"utf-8\" ?>\r\n<root/>");
+
+        let mut xml = XmlWriter::new(Vec::new());
+        xml.pretty = false;
+        xml.dtd("utf-8");
+        xml.elem("root");
+
+        let actual = xml.into_inner();
+        assert_eq!(str::from_utf8(&actual).unwrap(), "<?xml version=\"1.0\" encoding=\"utf-8\" ?><root/>");
+    }
+
+    #[test]
+    fn elem_text_each() {
+        let mut xml = XmlWriter::new(Vec::new());
+        xml.pretty = false;
+        xml.begin_elem("row");
+            xml.elem_text_each("cell", &["1", "a&b"]);
+        xml.end_elem();
+
+        let actual = xml.into_inner();
+        assert_eq!(str::from_utf8(&actual).unwrap(), "<row><cell>1</cell><cell>a&amp;b</cell></row>");
+    }
+
+    #[test]
+    fn elem_raw_body() {
+        let mut xml = XmlWriter::new(Vec::new());
+        xml.begin_elem("post");
+            xml.elem_raw_body("div", "<p>Hi &amp; <b>bye</b></p>");
+        xml.end_elem();
+
+        let actual = xml.into_inner();
+        assert_eq!(str::from_utf8(&actual).unwrap(), "<post>\n  <div><p>Hi &amp; <b>bye</b></p></div></post>");
+    }
+
+    #[test]
+    fn elem_text_opt() {
+        let mut xml = XmlWriter::new(Vec::new());
+        xml.pretty = false;
+        xml.begin_elem("person");
+            xml.elem_text_opt("name", Some("Jo"));
+            xml.elem_text_opt("email", None);
+        xml.end_elem();
+
+        let actual = xml.into_inner();
+        assert_eq!(str::from_utf8(&actual).unwrap(), "<person><name>Jo</name></person>");
+    }
+
+    #[test]
+    fn attr_after_child() {
+        let mut xml = XmlWriter::new(Vec::new());
+        xml.begin_elem("parent");
+            xml.begin_elem("child");
+            xml.end_elem();
+            let err = xml.attr("id", "1").unwrap_err();
+            assert_eq!(err.get_ref().unwrap().downcast_ref::<XmlError>(), Some(&XmlError::ElementAlreadyClosed("parent".to_string())));
+            assert!(xml.attr_esc("id", "1").is_err());
+            assert!(xml.ns_decl(&vec![(None, "http://localhost/")]).is_err());
+        xml.end_elem();
+
+        let actual = xml.into_inner();
+        assert_eq!(str::from_utf8(&actual).unwrap(), "<parent>\n  <child></child></parent>");
+    }
+
+    #[test]
+    fn current_and_parent_elem() {
+        let mut xml = XmlWriter::new(Vec::new());
+        assert_eq!(xml.current_elem(), None);
+        xml.begin_elem("root");
+        assert_eq!(xml.current_elem(), Some("root"));
+        assert_eq!(xml.parent_elem(), None);
+        xml.begin_elem("st:child");
+        assert_eq!(xml.current_elem(), Some("child"));
+        assert_eq!(xml.parent_elem(), Some("root"));
+    }
+
+    #[test]
+    fn start_document() {
+        let mut xml = XmlWriter::new(Vec::new());
+        xml.final_newline = true;
+        xml.start_document();
+        xml.dtd("utf-8");
+        xml.begin_elem("a");
+            xml.elem("b");
+        xml.end_document();
+        assert!(xml.dtd("utf-8").is_err());
+
+        xml.start_document();
+        xml.dtd("utf-8");
+        xml.elem("c");
+        let err = xml.elem("d").unwrap_err();
+        assert_eq!(err.get_ref().unwrap().downcast_ref::<XmlError>(), Some(&XmlError::MultipleRoots));
+        xml.end_document();
+
+        let actual = xml.into_inner();
+        assert_eq!(str::from_utf8(&actual).unwrap(), "<?xml version=\"1.0\" encoding=\"utf-8\" ?>\n<a>\n  <b/></a>\n<?xml version=\"1.0\" encoding=\"utf-8\" ?>\n<c/>\n");
+    }
+
+    #[test]
+    fn prolog_items() {
+        let mut xml = XmlWriter::new(Vec::new());
+        xml.dtd("utf-8");
+        xml.prolog_items(&[
+            PrologItem::Comment("generated"),
+            PrologItem::Doctype("note", Some("SYSTEM \"note.dtd\"")),
+            PrologItem::Pi("xml-stylesheet", "href=\"a.xsl\""),
+        ]);
+        xml.elem("note");
+        assert!(xml.prolog_items(&[PrologItem::Comment("late")]).is_err());
+        assert!(xml.doctype("note", None).is_err());
+
+        let actual = xml.into_inner();
+        assert_eq!(str::from_utf8(&actual).unwrap(), "<?xml version=\"1.0\" encoding=\"utf-8\" ?>\n<!-- generated -->\n<!DOCTYPE note SYSTEM \"note.dtd\">\n<?xml-stylesheet href=\"a.xsl\"?>\n<note/>");
+    }
+
+    #[test]
+    fn write_bytes() {
+        let mut xml = XmlWriter::new(Vec::new());
+        xml.write_bytes("<ä/>".as_bytes());
+        assert!(xml.write_bytes(&[b'<', 0xff]).is_err());
+        xml.write_bytes_unchecked(&[0xff]);
+
+        let actual = xml.into_inner();
+        assert_eq!(actual, b"<\xc3\xa4/>\xff");
+    }
+
+    #[test]
+    fn pi_attrs() {
+        let mut xml = XmlWriter::new(Vec::new());
+        xml.stylesheet("text/xsl", "a&b.xsl");
+        assert!(xml.stylesheet("text/xsl", "a?>b").is_err());
+        assert!(xml.pi_attrs("target", &[("a b", "1")]).is_err());
+
+        let actual = xml.into_inner();
+        assert_eq!(str::from_utf8(&actual).unwrap(), "<?xml-stylesheet type=\"text/xsl\" href=\"a&amp;b.xsl\"?>");
+    }
+
+    #[test]
+    fn xml_model() {
+        let mut xml = XmlWriter::new(Vec::new());
+        xml.xml_model("schema.rng?v=1&x", "application/xml");
+        xml.elem("doc");
+        assert!(xml.xml_model("late.rng", "application/xml").is_err());
+
+        let actual = xml.into_inner();
+        assert_eq!(str::from_utf8(&actual).unwrap(), "<?xml-model href=\"schema.rng?v=1&amp;x\" type=\"application/xml\"?>\n<doc/>");
+    }
+
+    #[test]
+    fn as_ref_str() {
+        let name = String::from("lang");
+        let mut xml = XmlWriter::new(Vec::new());
+        xml.pretty = false;
+        xml.begin_elem("p");
+            xml.attr(&name, Cow::Borrowed("en"));
+            xml.attr_esc("title", format!("{}&{}", 1, 2));
+            xml.attr_bool(String::from("on"), true);
+            xml.attr_display(Cow::Borrowed("n"), 1);
+            xml.id(format!("p{}", 1));
+            assert_eq!(xml.attr_counted(&name[..1], String::from("1")).unwrap(), 6);
+            xml.comment(String::from("note"));
+            xml.text(Cow::Owned::<str>(String::from("a<b")));
+            xml.cdata(String::from("raw"));
+        xml.end_elem();
+
+        let actual = xml.into_inner();
+        assert_eq!(str::from_utf8(&actual).unwrap(), "<p lang=\"en\" title=\"1&amp;2\" on=\"true\" n=\"1\" xml:id=\"p1\" l=\"1\"><!-- note -->a&lt;b<![CDATA[raw]]></p>");
+    }
+
+    #[test]
+    fn empty_text() {
+        let mut xml = XmlWriter::new(Vec::new());
+        xml.pretty = false;
+        xml.begin_elem("x");
+            xml.text("");
+            xml.attr("a", "1");
+        xml.end_elem();
+        xml.text("");
+
+        let actual = xml.into_inner();
+        assert_eq!(str::from_utf8(&actual).unwrap(), "<x a=\"1\"></x>");
+    }
+
+    #[test]
+    fn has_content() {
+        let mut xml = XmlWriter::new(Vec::new());
+        assert!(!xml.has_content());
+        xml.elem("first");
+        assert!(xml.has_content());
+        xml.reset();
+        assert!(!xml.has_content());
+        xml.elem("second");
+
+        let actual = xml.into_inner();
+        assert_eq!(str::from_utf8(&actual).unwrap(), "<first/><second/>");
+    }
+
+    #[test]
+    fn begin_elem_attrs_raw() {
+        let mut xml = XmlWriter::new(Vec::new());
+        xml.begin_elem_attrs_raw("row", &[("id", "7"), ("label", "a&amp;b")]);
+        xml.end_elem();
+
+        let actual = xml.into_inner();
+        assert_eq!(str::from_utf8(&actual).unwrap(), "<row id=\"7\" label=\"a&amp;b\"></row>");
+    }
+
+    #[test]
+    fn begin_elem_attrs() {
+        struct Server { host: String, port: &'static str }
+
+        impl AsAttributes for Server {
+            fn for_each_attr(&self, f: &mut dyn FnMut(&str, &str) -> io::Result<()>) -> io::Result<()> {
+                f("host", &self.host)?;
+                f("port", self.port)
+            }
+        }
+
+        let server = Server { host: "a&b".to_string(), port: "80" };
+        let mut xml = XmlWriter::new(Vec::new());
+        xml.begin_elem_attrs("server", &server);
+            xml.begin_elem_attrs("alias", &[("name", "www")]);
+            xml.end_elem();
+        xml.end_elem();
+
+        let actual = xml.into_inner();
+        assert_eq!(str::from_utf8(&actual).unwrap(), "<server host=\"a&amp;b\" port=\"80\">\n  <alias name=\"www\"></alias></server>");
+    }
+
+    #[test]
+    fn close_twice() {
+        let mut xml = XmlWriter::new(Vec::new());
+        xml.begin_elem("root");
+            xml.begin_elem("child");
+        assert!(xml.close().is_ok());
+        assert!(xml.close().is_ok());
+
+        let actual = xml.into_inner();
+        assert_eq!(str::from_utf8(&actual).unwrap(), "<root>\n  <child></child></root>");
+    }
+
+    #[test]
+    fn indent_unit() {
+        let mut xml = XmlWriter::new(Vec::new());
+        xml.indent_unit = "│ ";
+        xml.begin_elem("a");
+            xml.begin_elem("b");
+                xml.elem("c");
+        xml.close();
+
+        let actual = xml.into_inner();
+        assert_eq!(str::from_utf8(&actual).unwrap(), "<a>\n│ <b>\n│ │ <c/></b></a>");
+    }
+
+    #[test]
+    fn max_render_depth() {
+        let mut xml = XmlWriter::new(Vec::new());
+        xml.max_render_depth = Some(2);
+        xml.begin_elem("root");
+            xml.begin_elem("a");
+                xml.attr("id", "1");
+                xml.text("a");
+                xml.begin_elem("deep");
+                    xml.attr("id", "2");
+                    xml.elem_text("deeper", "x");
+                xml.end_elem();
+                xml.elem("deep");
+            xml.end_elem();
+            xml.begin_elem("b");
+                xml.elem("deep");
+            xml.end_elem();
+            xml.elem("c");
+        xml.end_elem();
+
+        let actual = xml.into_inner();
+        assert_eq!(str::from_utf8(&actual).unwrap(), "<root>\n  <a id=\"1\">a\n    <!-- ... --></a>\n  <b>\n    <!-- ... --></b>\n  <c/></root>");
+    }
+
+    #[test]
+    fn name_counter() {
+        let counter = NameCounter::new();
+        let mut xml = XmlWriter::new(Vec::new());
+        xml.observe(counter.clone());
+        xml.begin_elem("table");
+        for _ in 0..3 {
+            xml.begin_elem("row");
+                xml.elem_text_each("cell", &["a", "b"]);
+            xml.end_elem();
+        }
+        xml.elem("footer");
+        xml.close();
+        assert!(xml.begin_elem("second").is_err());
+
+        assert_eq!(counter.count("table"), 1);
+        assert_eq!(counter.count("row"), 3);
+        assert_eq!(counter.count("cell"), 6);
+        assert_eq!(counter.count("footer"), 1);
+        assert_eq!(counter.count("second"), 0);
+        assert_eq!(counter.counts().len(), 4);
+    }
+
+    #[test]
+    fn top_level_text() {
+        let mut xml = XmlWriter::new(Vec::new());
+        let err = xml.text("text").unwrap_err();
+        assert_eq!(err.get_ref().unwrap().downcast_ref::<XmlError>(), Some(&XmlError::TextOutsideRoot));
+        assert!(xml.text("\u{A0}").is_err());
+        assert!(xml.cdata("data").is_err());
+        assert!(xml.write_entity("nbsp").is_err());
+        xml.text("\n");
+        xml.fragment = true;
+        xml.text("text");
+        xml.elem("a");
+
+        let actual = xml.into_inner();
+        assert_eq!(str::from_utf8(&actual).unwrap(), "\ntext\n<a/>");
+    }
+
+    #[test]
+    fn poisoned() {
+        struct Failing { left: usize }
+
+        impl io::Write for Failing {
+            fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+                if self.left == 0 {
+                    return Err(io::Error::new(io::ErrorKind::BrokenPipe, "gone"));
+                }
+                let len = buf.len().min(self.left);
+                self.left -= len;
+                Ok(len)
+            }
+
+            fn flush(&mut self) -> io::Result<()> { Ok(()) }
+        }
+
+        let mut xml = XmlWriter::new(Failing { left: 8 });
+        xml.begin_elem("root");
+        assert!(!xml.is_poisoned());
+        assert_eq!(xml.text("some text").unwrap_err().kind(), io::ErrorKind::BrokenPipe);
+        assert!(xml.is_poisoned());
+        assert_eq!(xml.elem("a").unwrap_err().kind(), io::ErrorKind::BrokenPipe);
+        assert_eq!(xml.flush().unwrap_err().kind(), io::ErrorKind::BrokenPipe);
+        assert_eq!(io::Write::write(&mut xml, b"<raw/>").unwrap_err().kind(), io::ErrorKind::BrokenPipe);
+        assert_eq!(io::Write::flush(&mut xml).unwrap_err().kind(), io::ErrorKind::BrokenPipe);
+
+        let err = xml.take_error().unwrap();
+        assert_eq!(err.to_string(), "gone");
+        assert!(!xml.is_poisoned());
+
+        let mut xml = XmlWriter::new(Failing { left: 2 });
+        xml.ns_decls_first = true;
+        xml.begin_elem("r");
+        assert!(xml.ns_decl(&vec![(Some("a"), "urn:a")]).is_err());
+        assert_eq!(xml.attr("b", "1").unwrap_err().kind(), io::ErrorKind::BrokenPipe);
+
+        let mut xml = XmlWriter::new(Failing { left: 0 });
+        assert!(io::Write::write(&mut xml, b"<raw/>").is_err());
+        assert!(xml.is_poisoned());
+    }
+
+    #[test]
+    fn id() {
+        let mut xml = XmlWriter::new(Vec::new());
+        xml.begin_elem("section");
+            xml.id("intro");
+            assert!(xml.id("1st").is_err());
+            assert!(xml.id("a:b").is_err());
+        xml.end_elem();
+
+        let actual = xml.into_inner();
+        assert_eq!(str::from_utf8(&actual).unwrap(), "<section xml:id=\"intro\"></section>");
+    }
+
+    #[test]
+    fn default_namespace() {
+        let mut xml = XmlWriter::new(Vec::new());
+        xml.default_namespace = Some("urn:a");
+        xml.begin_elem("root");
+            xml.elem("same");
+            xml.default_namespace = Some("urn:b");
+            xml.begin_elem("other");
+                xml.elem_text("inherited", "b");
+            xml.end_elem();
+            xml.default_namespace = Some("urn:a");
+            xml.elem("back");
+            xml.default_namespace = None;
+            xml.elem("none");
+        xml.end_elem();
+
+        let actual = xml.into_inner();
+        assert_eq!(str::from_utf8(&actual).unwrap(), "<root xmlns=\"urn:a\">\n  <same/>\n  <other xmlns=\"urn:b\">\n    <inherited>b</inherited></other>\n  <back/>\n  <none xmlns=\"\"/></root>");
+    }
+
+    #[test]
+    fn default_namespace_declared_once() {
+        let mut xml = XmlWriter::new(Vec::new());
+        xml.pretty = false;
+        xml.default_namespace = Some("urn:a");
+        xml.begin_elem("r");
+            let err = xml.ns_decl(&vec![(None, "urn:b")]).unwrap_err();
+            assert_eq!(err.get_ref().unwrap().downcast_ref::<XmlError>(), Some(&XmlError::DuplicateAttr("xmlns".to_string())));
+            xml.ns_decl(&vec![(Some("b"), "urn:b")]);
+            xml.begin_elem("c");
+                xml.ns_decl(&vec![(None, "urn:c")]);
+                assert!(xml.ns_decl(&vec![(None, "urn:d")]).is_err());
+            xml.end_elem();
+        xml.end_elem();
+
+        let actual = xml.into_inner();
+        assert_eq!(str::from_utf8(&actual).unwrap(), "<r xmlns=\"urn:a\" xmlns:b=\"urn:b\"><c xmlns=\"urn:c\"></c></r>");
+    }
+
+    #[test]
+    fn bool_style() {
+        let mut xml = XmlWriter::new(Vec::new());
+        xml.pretty = false;
+        xml.begin_elem("flags");
+            xml.attr_bool("on", true);
+            xml.elem_bool("a", false);
+            xml.bool_style = BoolStyle::OneZero;
+            xml.elem_bool("b", true);
+            xml.bool_style = BoolStyle::YesNo;
+            xml.elem_bool("c", false);
+        xml.end_elem();
+
+        let actual = xml.into_inner();
+        assert_eq!(str::from_utf8(&actual).unwrap(), "<flags on=\"true\"><a>false</a><b>1</b><c>no</c></flags>");
+    }
+
+    #[test]
+    fn blank_line_between_top_level() {
+        let mut xml = XmlWriter::new(Vec::new());
+        xml.blank_line_between_top_level = true;
+        xml.begin_elem("config");
+            xml.begin_elem("server");
+                xml.elem("host");
+                xml.elem("port");
+            xml.end_elem();
+            xml.comment("logging");
+            xml.elem("log");
+        xml.end_elem();
+
+        let actual = xml.into_inner();
+        assert_eq!(str::from_utf8(&actual).unwrap(), "<config>\n  <server>\n    <host/>\n    <port/></server>\n\n  <!-- logging -->\n\n  <log/></config>");
+    }
+
+    #[test]
+    fn auto_flush_depth() {
+        struct Flushes(Vec<u8>, Vec<usize>);
+        impl io::Write for Flushes {
+            fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+                self.0.write(buf)
+            }
+            fn flush(&mut self) -> io::Result<()> {
+                self.1.push(self.0.len());
+                Ok(())
+            }
+        }
+
+        let mut xml = XmlWriter::new(Flushes(Vec::new(), Vec::new()));
+        xml.pretty = false;
+        xml.auto_flush_depth = Some(2);
+        xml.begin_elem("stream");
+            xml.begin_elem("msg");
+                xml.elem("body");
+            xml.end_elem();
+            xml.elem_text("msg", "hi");
+            xml.elem("ping");
+        xml.end_elem();
+
+        let actual = xml.into_inner();
+        assert_eq!(str::from_utf8(&actual.0).unwrap(), "<stream><msg><body/></msg><msg>hi</msg><ping/></stream>");
+        assert_eq!(actual.1, vec![26, 39, 46]);
+    }
+
+    #[test]
+    fn finish() {
+        let mut xml = XmlWriter::new(Vec::new());
+        xml.begin_elem("a");
+            xml.elem("b");
+        let actual = xml.finish().unwrap();
+        assert_eq!(str::from_utf8(&actual).unwrap(), "<a>\n  <b/></a>");
+
+        let mut xml = XmlWriter::new(Vec::new());
+        xml.comment("nothing");
+        assert_eq!(xml.finish().unwrap_err().to_string(), "no root element");
+
+        let mut xml = XmlWriter::new(Vec::new());
+        xml.fragment = true;
+        xml.text("loose");
+        assert_eq!(xml.finish().unwrap(), b"loose");
+    }
+
+    #[test]
+    fn attr_value_esc() {
+        let mut xml = XmlWriter::new(Vec::new());
+        xml.begin_elem("a");
+            xml.attr_value_esc("st:title", "<\"x\">");
+            assert!(xml.attr_value_esc("a b", "1").is_err());
+        xml.end_elem();
+
+        let actual = xml.into_inner();
+        assert_eq!(str::from_utf8(&actual).unwrap(), "<a st:title=\"&lt;&quot;x&quot;&gt;\"></a>");
+    }
+
+    #[test]
+    fn backslash() {
+        let mut xml = XmlWriter::new(Vec::new());
+        xml.begin_elem("path");
+            xml.attr_esc("win\\dir", "C:\\Temp");
+            xml.text("\\n");
+        xml.end_elem();
+
+        let actual = xml.into_inner();
+        assert_eq!(str::from_utf8(&actual).unwrap(), "<path win\\dir=\"C:\\Temp\">\\n</path>");
+    }
+
+    #[test]
+    fn write_attrs() {
+        let mut attrs = AttrSet::sorted(Duplicates::LastWins);
+        attrs.push("z", "1");
+        attrs.push("a", "2");
+        attrs.push("z", "3&");
+        let mut strict = AttrSet::new(Duplicates::Error);
+        strict.push("z", "1");
+        strict.push("a", "2");
+        assert!(strict.push("z", "3").is_err());
+
+        let mut xml = XmlWriter::new(Vec::new());
+        xml.pretty = false;
+        xml.fragment = true;
+        xml.begin_elem("sorted");
+            xml.write_attrs(&attrs);
+        xml.end_elem();
+        xml.begin_elem_attrs("ordered", &strict);
+        xml.end_elem();
+
+        let actual = xml.into_inner();
+        assert_eq!(str::from_utf8(&actual).unwrap(), "<sorted a=\"2\" z=\"3&amp;\"></sorted><ordered z=\"1\" a=\"2\"></ordered>");
+    }
+
+    #[test]
+    fn counted() {
+        let mut xml = XmlWriter::new(Vec::new());
+        xml.begin_elem("a");
+        assert_eq!(xml.attr_counted("id", "1").unwrap(), 7);
+        assert_eq!(xml.attr_esc_counted("q", "\"").unwrap(), 11);
+        assert_eq!(xml.text_counted("<&>").unwrap(), 14);
+        assert_eq!(xml.bytes_written(), 34);
+
+        let actual = xml.into_inner();
+        assert_eq!(actual.len(), 34);
+    }
+
+    #[test]
+    fn map_names() {
+        let mut xml = XmlWriter::new(Vec::new());
+        xml.map_names(|name| if name.starts_with("x-") { Cow::Borrowed(name) } else { Cow::Owned(format!("x-{}", name)) });
+        xml.namespace = Some("st");
+        xml.begin_elem("root");
+            xml.elem("a");
+            xml.elem_text("b", "text");
+            xml.empty_elem("x-c");
+        xml.end_elem();
+
+        let actual = xml.into_inner();
+        assert_eq!(str::from_utf8(&actual).unwrap(), "<st:x-root>\n  <st:x-a/>\n  <st:x-b>text</st:x-b>\n  <st:x-c/></st:x-root>");
+    }
+
+    #[test]
+    fn ns_decls_first() {
+        let mut xml = XmlWriter::new(Vec::new());
+        xml.ns_decls_first = true;
+        xml.begin_elem("root");
+            xml.attr("id", "1");
+            xml.ns_decl(&vec![(Some("st"), "http://127.0.0.1/")]);
+            xml.attr_esc("name", "a&b");
+            xml.elem("child");
+        xml.end_elem();
+
+        let actual = xml.into_inner();
+        assert_eq!(str::from_utf8(&actual).unwrap(), "<root xmlns:st=\"http://127.0.0.1/\" id=\"1\" name=\"a&amp;b\">\n  <child/></root>");
+    }
+
+    #[test]
+    fn ns_decl_escapes_uri() {
+        let mut xml = XmlWriter::new(Vec::new());
+        xml.begin_elem("root");
+            xml.ns_decl(&vec![(None, "http://localhost/?a=1&b=2")]);
+        xml.end_elem();
+
+        let actual = xml.into_inner();
+        assert_eq!(str::from_utf8(&actual).unwrap(), "<root xmlns=\"http://localhost/?a=1&amp;b=2\"></root>");
+    }
+
+    #[test]
+    fn ns_decl_after_attr() {
+        let mut xml = XmlWriter::new(Vec::new());
+        xml.begin_elem("root");
+            xml.ns_decl(&vec![(Some("a"), "urn:a")]);
+            xml.attr("id", "1");
+            assert_eq!(xml.ns_decl(&vec![(Some("b"), "urn:b")]).unwrap_err().to_string(),
+                "namespace declarations must precede the attributes");
+            xml.begin_elem("child");
+                xml.ns_decl(&vec![(Some("c"), "urn:c")]);
+            xml.end_elem();
+        xml.end_elem();
+
+        let actual = xml.into_inner();
+        assert_eq!(str::from_utf8(&actual).unwrap(), "<root xmlns:a=\"urn:a\" id=\"1\">\n  <child xmlns:c=\"urn:c\"></child></root>");
+    }
+
+    #[test]
+    fn xinclude() {
+        let mut xml = XmlWriter::new(Vec::new());
+        xml.begin_elem("doc");
+            xml.xinclude("chapter.xml?part=1&lang=en");
+        xml.end_elem();
+
+        let actual = xml.into_inner();
+        assert_eq!(str::from_utf8(&actual).unwrap(), "<doc>\n  <xi:include xmlns:xi=\"http://www.w3.org/2001/XInclude\" href=\"chapter.xml?part=1&amp;lang=en\"/></doc>");
+    }
+
+    #[test]
+    fn flush_mid_document() {
+        let mut xml = XmlWriter::new(Vec::new());
+        xml.ns_decls_first = true;
+        xml.begin_elem("stream");
+            xml.attr("id", "1");
+            xml.flush();
+            assert_eq!(str::from_utf8(xml.writer.as_ref()).unwrap(), "<stream id=\"1\">");
+            xml.elem_text("event", "a");
+            xml.flush();
+            xml.elem_text("event", "b");
+        xml.close();
+
+        let actual = xml.into_inner();
+        assert_eq!(str::from_utf8(&actual).unwrap(), "<stream id=\"1\">\n  <event>a</event>\n  <event>b</event></stream>");
+    }
+
+    #[test]
+    fn elem_display() {
+        let mut xml = XmlWriter::new(Vec::new());
+        xml.pretty = false;
+        xml.fragment = true;
+        xml.elem_display("amount", 12.5);
+        xml.elem_display("cmp", format_args!("{}<{}", 1, 2));
+
+        // formatted once to check it and once to write it
+        struct Counted<'c>(&'c Cell<usize>);
+        impl<'c> fmt::Display for Counted<'c> {
+            fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                self.0.set(self.0.get() + 1);
+                f.write_str("n")
+            }
+        }
+        let calls = Cell::new(0);
+        xml.elem_display("c", Counted(&calls));
+        assert_eq!(calls.get(), 2);
+
+        let actual = xml.into_inner();
+        assert_eq!(str::from_utf8(&actual).unwrap(), "<amount>12.5</amount><cmp>1&lt;2</cmp><c>n</c>");
+    }
+
+    #[test]
+    fn attr_display() {
+        let mut xml = XmlWriter::new(Vec::new());
+        xml.begin_elem("item");
+            xml.attr_display("qty", 3u32);
+            xml.attr_display("price", format_args!("{:.2}", 1e10));
+            xml.attr_display("cmp", format_args!("{}<{}", 1, 2));
+            assert!(xml.attr_display("a b", 1).is_err());
+        xml.end_elem();
+
+        let actual = xml.into_inner();
+        assert_eq!(str::from_utf8(&actual).unwrap(), "<item qty=\"3\" price=\"10000000000.00\" cmp=\"1&lt;2\"></item>");
+    }
+
+    #[test]
+    fn comment_wrap() {
+        let mut xml = XmlWriter::new(Vec::new());
+        xml.comment_wrap = Some(12);
+        xml.begin_elem("root");
+            xml.comment("short");
+            xml.comment("a rather long - comment, wrapped at spaces");
+            xml.comment("abcdefgh  ijklmnop qr  s");
+            xml.elem("a");
+            xml.set_pretty(false);
+            xml.comment("not wrapped when compact");
+        xml.end_elem();
+
+        let actual = xml.into_inner();
+        assert_eq!(str::from_utf8(&actual).unwrap(), "<root>\n  <!-- short -->\n  <!-- a rather\n  long -\n  comment,\n  wrapped at\n  spaces -->\n  <!-- abcdefgh\n  ijklmnop qr\n  s -->\n  <a/><!-- not wrapped when compact --></root>");
+    }
+
+    #[test]
+    fn attr_fmt() {
+        let (base, modifier) = ("btn", "btn-<primary>");
+        let mut xml = XmlWriter::new(Vec::new());
+        xml.begin_elem("button");
+            xml.attr_fmt("class", format_args!("{} {}", base, modifier));
+        xml.end_elem();
+
+        let actual = xml.into_inner();
+        assert_eq!(str::from_utf8(&actual).unwrap(), "<button class=\"btn btn-&lt;primary&gt;\"></button>");
+    }
+
+    #[test]
+    fn interner() {
+        let calls = Cell::new(0);
+        let mut xml = XmlWriter::with_interner(Vec::new());
+        xml.pretty = false;
+        xml.map_names(|name| {
+            calls.set(calls.get() + 1);
+            Cow::Owned(name.to_uppercase())
+        });
+        xml.begin_elem("table");
+        for _ in 0..3 {
+            xml.begin_elem("row");
+                xml.elem_text("cell", "1");
+            xml.end_elem();
+        }
+        xml.end_elem();
+
+        let actual = xml.into_inner();
+        assert_eq!(str::from_utf8(&actual).unwrap(), "<TABLE><ROW><CELL>1</CELL></ROW><ROW><CELL>1</CELL></ROW><ROW><CELL>1</CELL></ROW></TABLE>");
+        assert_eq!(calls.get(), 3);
+    }
+
+    #[test]
+    fn interner_remap() {
+        let mut xml = XmlWriter::with_interner(Vec::new());
+        xml.pretty = false;
+        xml.fragment = true;
+        xml.map_names(|name| Cow::Owned(name.to_uppercase()));
+        xml.elem("a");
+        xml.map_names(|name| Cow::Owned(format!("x-{}", name)));
+        xml.elem("a");
+
+        let actual = xml.into_inner();
+        assert_eq!(str::from_utf8(&actual).unwrap(), "<A/><x-a/>");
+    }
+
+    #[test]
+    fn prefixed_name() {
+        let mut xml = XmlWriter::new(Vec::new());
+        xml.namespace = Some("other");
+        xml.begin_elem("st:node");
+        xml.end_elem();
+
+        let actual = xml.into_inner();
+        assert_eq!(str::from_utf8(&actual).unwrap(), "<st:node></st:node>");
+    }
+
+    #[test]
+    fn invalid_prefixed_name() {
+        let mut xml = XmlWriter::new(Vec::new());
+        assert!(xml.begin_elem("st:no:de").is_err());
+        assert!(xml.begin_elem("1st:node").is_err());
+        assert!(xml.begin_elem(":node").is_err());
+
+        let actual = xml.into_inner();
+        assert_eq!(str::from_utf8(&actual).unwrap(), "");
+    }
 }
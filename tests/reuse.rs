@@ -0,0 +1,57 @@
+extern crate xml_writer;
+
+use std::alloc::{ GlobalAlloc, Layout, System };
+use std::io;
+use std::sync::atomic::{ AtomicUsize, Ordering };
+use xml_writer::XmlWriter;
+
+struct Counting;
+
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for Counting {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::SeqCst);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::SeqCst);
+        System.realloc(ptr, layout, new_size)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: Counting = Counting;
+
+#[allow(unused_must_use)]
+fn document(xml: &mut XmlWriter<io::Sink>) {
+    xml.reset();
+    xml.comment("report");
+    xml.begin_elem("report");
+        xml.attr_esc("title", "a & b");
+        for _ in 0..10 {
+            xml.begin_elem("row");
+                xml.elem_text("cell", "<1>");
+                xml.cdata("raw");
+            xml.end_elem();
+        }
+    xml.end_elem();
+    xml.flush();
+}
+
+#[test]
+fn reset_is_allocation_free() {
+    let mut xml = XmlWriter::new(io::sink());
+    document(&mut xml);
+
+    let before = ALLOCATIONS.load(Ordering::SeqCst);
+    for _ in 0..100 {
+        document(&mut xml);
+    }
+    assert_eq!(ALLOCATIONS.load(Ordering::SeqCst), before);
+}